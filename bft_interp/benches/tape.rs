@@ -0,0 +1,43 @@
+//! Compares the dense and the sparse tape storage.
+
+use bft_interp::{Storage, VirtualMachine};
+use bft_types::Program;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+/// Busy loops on a few cells, where the dense storage should win.
+const HOT_LOOP: &str = "++++++++[>++++++++[>++++++++[>+<-]<-]<-]";
+
+/// Walks right over fresh cells, growing the tape all the way.
+const WALK: &str = "+[>+>>>>>>>>>>>>>>>[-]<<<<<<<<<<<<<<<[-]>>>>>>>>>>>>>>>+]";
+
+/// Runs `code` on a growable tape of 16 cells with the given storage.
+fn run(code: &str, storage: Storage, steps: u64) {
+    let program = Program::new("bench.bf".to_string(), code);
+    let mut vm = VirtualMachine::builder()
+        .tape_size(16)
+        .growable(true)
+        .storage(storage)
+        .step_limit(steps)
+        .build(program)
+        .unwrap();
+    // The walk never ends by itself, it stops at the step limit
+    let _ = vm.run();
+}
+
+fn tape_storage(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tape");
+    for storage in [Storage::Dense, Storage::Sparse] {
+        group.bench_with_input(
+            BenchmarkId::new("hot_loop", storage),
+            &storage,
+            |b, storage| b.iter(|| run(HOT_LOOP, *storage, u64::MAX)),
+        );
+        group.bench_with_input(BenchmarkId::new("walk", storage), &storage, |b, storage| {
+            b.iter(|| run(WALK, *storage, 200_000))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tape_storage);
+criterion_main!(benches);
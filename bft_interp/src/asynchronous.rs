@@ -0,0 +1,225 @@
+//! Async variant of the interpreter, for running programs inside async
+//! services. Enabled with the `async` feature.
+
+use crate::{io_error, CellKind, FlushPolicy, VMError, VirtualMachine};
+use bft_types::Opcode;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Number of instructions we execute before giving the executor a chance to
+/// run other tasks.
+const YIELD_EVERY: u64 = 10_000;
+
+impl<C: CellKind> VirtualMachine<C> {
+    /// Executes the program like [`VirtualMachine::interpret`], with async
+    /// input and output.
+    ///
+    /// `,` waits for the input without blocking the thread, and long running
+    /// programs yield to the executor every 10000 instructions. The output is
+    /// flushed following the flush policy, before waiting for the input and
+    /// at the end.
+    pub async fn interpret_async<R, W>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<(), VMError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if self.ip != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Program already executed.",
+            ));
+        }
+
+        let res = self.execute_async(input, output).await;
+        // The first error wins, a failed flush only matters after a good run
+        let flushed = output.flush().await;
+        res.and(flushed)
+    }
+
+    /// Executes the instructions for [`VirtualMachine::interpret_async`],
+    /// without the last flush.
+    async fn execute_async<R, W>(&mut self, input: &mut R, output: &mut W) -> Result<(), VMError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut since_yield = 0;
+        while let Some(ins) = self.current_instruction() {
+            let span = ins.span;
+            match ins.op {
+                Opcode::Input => {
+                    output
+                        .flush()
+                        .await
+                        .map_err(|e| io_error(e, "Output", &self.prg.describe(&span)))?;
+                    let mut buf = [0; 1];
+                    let read = input
+                        .read(&mut buf)
+                        .await
+                        .map_err(|e| io_error(e, "Input", &self.prg.describe(&span)))?;
+                    // An empty reader makes the step apply the EOF mode
+                    self.step(&mut &buf[..read], &mut std::io::sink())?;
+                }
+                Opcode::Output => {
+                    let mut buf = Vec::with_capacity(1);
+                    self.step(&mut std::io::empty(), &mut buf)?;
+                    let flush = match self.flush {
+                        FlushPolicy::Byte => true,
+                        FlushPolicy::Line => buf == b"\n",
+                        FlushPolicy::Exit => false,
+                    };
+                    let res = match output.write_all(&buf).await {
+                        Ok(()) if flush => output.flush().await,
+                        res => res,
+                    };
+                    res.map_err(|e| io_error(e, "Output", &self.prg.describe(&span)))?;
+                }
+                _ => {
+                    self.step(&mut std::io::empty(), &mut std::io::sink())?;
+                }
+            }
+            since_yield += 1;
+            if since_yield == YIELD_EVERY {
+                since_yield = 0;
+                YieldNow(false).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A future which is pending once, so the executor can run other tasks
+/// before polling us again. It works with any executor.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{EofMode, VirtualMachine};
+    use bft_types::Program;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::io::AsyncWrite;
+
+    /// Only compiles if the value can move to another thread.
+    fn assert_send<T: Send>(_: T) {}
+
+    #[test]
+    fn future_is_send() {
+        let p = Program::new("test.bf".to_string(), ",.");
+        let mut vm = VirtualMachine::builder()
+            .cell_width::<u16>()
+            .build(p)
+            .unwrap();
+        let mut input = tokio::io::empty();
+        let mut output = tokio::io::sink();
+        assert_send(vm.interpret_async(&mut input, &mut output));
+    }
+
+    #[tokio::test]
+    async fn echo_async() {
+        let p = Program::new("test.bf".to_string(), ",[.,]");
+        let mut vm = VirtualMachine::builder()
+            .eof(EofMode::Zero)
+            .build(p)
+            .unwrap();
+        let mut output = Vec::new();
+        vm.interpret_async(&mut &b"hello"[..], &mut output)
+            .await
+            .unwrap();
+        assert_eq!(output, b"hello");
+    }
+
+    #[tokio::test]
+    async fn waits_for_input() {
+        let (mut writer, mut reader) = tokio::io::duplex(16);
+        let p = Program::new("test.bf".to_string(), ",.");
+        let mut vm = VirtualMachine::new(0, false, p);
+        let feeder = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tokio::io::AsyncWriteExt::write_all(&mut writer, b"A")
+                .await
+                .unwrap();
+        });
+        let mut output = Vec::new();
+        vm.interpret_async(&mut reader, &mut output).await.unwrap();
+        feeder.await.unwrap();
+        assert_eq!(output, b"A");
+    }
+
+    /// A writer which keeps what was flushed apart from what was written.
+    #[derive(Default)]
+    struct Flushed {
+        pending: Vec<u8>,
+        flushed: Vec<u8>,
+    }
+
+    impl AsyncWrite for Flushed {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.pending.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            let pending = std::mem::take(&mut self.pending);
+            self.flushed.extend(pending);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn flushes_on_error() {
+        let p = Program::new("test.bf".to_string(), "+++.<");
+        let mut vm = VirtualMachine::new(0, false, p);
+        let mut output = Flushed::default();
+        let error = vm
+            .interpret_async(&mut tokio::io::empty(), &mut output)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Already at the beginning of the tape.");
+        assert_eq!(output.flushed, [3]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn runaway_program_yields() {
+        let p = Program::new("test.bf".to_string(), "+[]");
+        let mut vm = VirtualMachine::new(0, false, p);
+        let mut input = tokio::io::empty();
+        let mut output = tokio::io::sink();
+        // On a single thread the timeout can only fire if the program yields
+        let res = tokio::time::timeout(
+            Duration::from_millis(20),
+            vm.interpret_async(&mut input, &mut output),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,300 @@
+//! Builder to configure and create a [`VirtualMachine`].
+
+use crate::{CellKind, EofMode, FlushPolicy, MemoryModel, Storage, VMError, VirtualMachine};
+use bft_types::Program;
+use std::fmt;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+/// The reader and writer a [`VirtualMachine`] uses in [`VirtualMachine::run`].
+#[derive(Default)]
+pub(crate) struct IoAdapters {
+    /// Where `,` reads from.
+    pub(crate) input: Option<Box<dyn Read + Send>>,
+    /// Where `.` writes to.
+    pub(crate) output: Option<Box<dyn Write + Send>>,
+    /// Where `#` prints the state of the machine, stderr if not set.
+    pub(crate) debug: Option<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for IoAdapters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoAdapters")
+            .field("input", &self.input.is_some())
+            .field("output", &self.output.is_some())
+            .field("debug", &self.debug.is_some())
+            .finish()
+    }
+}
+
+/// Configures a [`VirtualMachine`] step by step.
+///
+/// # Example
+///
+/// ```no_run
+/// # use bft_interp::{EofMode, VirtualMachine};
+/// # use bft_types::Program;
+///
+/// let program = Program::new("echo.bf".to_string(), ",[.,]");
+/// let mut vm = VirtualMachine::builder()
+///     .tape_size(100)
+///     .cell_width::<u16>()
+///     .eof(EofMode::Zero)
+///     .step_limit(10_000)
+///     .input(std::io::stdin())
+///     .output(std::io::stdout())
+///     .build(program)?;
+/// vm.run()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct VirtualMachineBuilder<C = u8> {
+    /// Number of cells, `0` means the default size.
+    size: usize,
+    /// If the machine can grow in size.
+    growable: bool,
+    /// If the size comes from the analysis of the program.
+    fit_tape: bool,
+    /// The shape of the memory.
+    memory: MemoryModel,
+    /// If the tape grows in both directions.
+    bidirectional: bool,
+    /// How the cells are stored.
+    storage: Storage,
+    /// Initial values of the first cells.
+    tape: Vec<u8>,
+    /// Initial position of the head.
+    head: usize,
+    /// What `,` does at the end of the input.
+    eof: EofMode,
+    /// Maximum number of instructions to execute.
+    step_limit: Option<u64>,
+    /// When to flush the output.
+    flush: FlushPolicy,
+    /// Capacity of the undo log, if we want one.
+    history: Option<usize>,
+    /// Reader and writer for `run`.
+    io: IoAdapters,
+    /// The type of the cells.
+    cell: PhantomData<C>,
+}
+
+impl<C: CellKind> Default for VirtualMachineBuilder<C> {
+    fn default() -> Self {
+        VirtualMachineBuilder {
+            size: 0,
+            growable: false,
+            fit_tape: false,
+            memory: MemoryModel::Tape,
+            bidirectional: false,
+            storage: Storage::Dense,
+            tape: Vec::new(),
+            head: 0,
+            eof: EofMode::default(),
+            step_limit: None,
+            flush: FlushPolicy::default(),
+            history: None,
+            io: IoAdapters::default(),
+            cell: PhantomData,
+        }
+    }
+}
+
+impl<C: CellKind> VirtualMachineBuilder<C> {
+    /// Creates a builder with the default settings, same as [`VirtualMachine::new`]
+    /// with size `0` and not growable.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of cells, `0` means 30000 cells.
+    pub fn tape_size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Sets if the machine can grow in size.
+    pub fn growable(mut self, growable: bool) -> Self {
+        self.growable = growable;
+        self
+    }
+
+    /// Sizes the tape to the cells the program can reach, as found by
+    /// [`Program::analyze_cells`], when the size is not set, the tape starts
+    /// empty with the head on the first cell, and the memory is a plain tape.
+    /// The default size stays when the analysis can not bound the head.
+    pub fn fit_tape(mut self, fit_tape: bool) -> Self {
+        self.fit_tape = fit_tape;
+        self
+    }
+
+    /// Sets the shape of the memory, a tape or a 2D grid.
+    pub fn memory(mut self, memory: MemoryModel) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Lets the tape grow on demand in both directions, so the head can move
+    /// left of the first cell. Cell indices stay relative to the first cell.
+    pub fn bidirectional(mut self, bidirectional: bool) -> Self {
+        self.bidirectional = bidirectional;
+        self
+    }
+
+    /// Chooses how the cells are stored, [`Storage::Sparse`] only keeps the
+    /// cells the program touched in memory.
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Changes the type of the cells, for example to `u16` or `u32`.
+    pub fn cell_width<D: CellKind>(self) -> VirtualMachineBuilder<D> {
+        VirtualMachineBuilder {
+            size: self.size,
+            growable: self.growable,
+            fit_tape: self.fit_tape,
+            memory: self.memory,
+            bidirectional: self.bidirectional,
+            storage: self.storage,
+            tape: self.tape,
+            head: self.head,
+            eof: self.eof,
+            step_limit: self.step_limit,
+            flush: self.flush,
+            history: self.history,
+            io: self.io,
+            cell: PhantomData,
+        }
+    }
+
+    /// Pre-loads the first cells of the tape with these values.
+    pub fn tape(mut self, tape: &[u8]) -> Self {
+        self.tape = tape.to_vec();
+        self
+    }
+
+    /// Sets the initial position of the head.
+    pub fn head(mut self, head: usize) -> Self {
+        self.head = head;
+        self
+    }
+
+    /// Sets what `,` does at the end of the input.
+    pub fn eof(mut self, eof: EofMode) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    /// Stops the machine with an error after executing this many instructions.
+    pub fn step_limit(mut self, limit: u64) -> Self {
+        self.step_limit = Some(limit);
+        self
+    }
+
+    /// Sets when the output of `.` is flushed, default after every newline.
+    pub fn flush_policy(mut self, flush: FlushPolicy) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Records the last `capacity` steps for reverse debugging.
+    pub fn history(mut self, capacity: usize) -> Self {
+        self.history = Some(capacity);
+        self
+    }
+
+    /// Sets the reader `,` uses in [`VirtualMachine::run`].
+    pub fn input<R: Read + Send + 'static>(mut self, input: R) -> Self {
+        self.io.input = Some(Box::new(input));
+        self
+    }
+
+    /// Sets the writer `.` uses in [`VirtualMachine::run`].
+    pub fn output<W: Write + Send + 'static>(mut self, output: W) -> Self {
+        self.io.output = Some(Box::new(output));
+        self
+    }
+
+    /// Sets the writer the debug instruction `#` prints to, instead of stderr.
+    pub fn debug_output<W: Write + Send + 'static>(mut self, debug: W) -> Self {
+        self.io.debug = Some(Box::new(debug));
+        self
+    }
+
+    /// Creates the virtual machine to run the given program.
+    ///
+    /// If the initial tape has more cells than the size, the machine gets
+    /// enough cells to hold all of it when it is growable, otherwise we return an
+    /// error. The head must point to a cell inside the tape. A grid always has
+    /// full rows, so the size is rounded up to a multiple of the width.
+    pub fn build(self, prog: Program) -> Result<VirtualMachine<C>, VMError> {
+        let mut size = self.size;
+        if self.fit_tape
+            && size == 0
+            && self.tape.is_empty()
+            && self.head == 0
+            && self.memory == MemoryModel::Tape
+            && !self.bidirectional
+        {
+            let cell_max = C::maximum().to_index() as u64;
+            if let Some(fit) = prog.analyze_cells(cell_max)?.tape_size() {
+                size = fit;
+            }
+        }
+        let mut vm = VirtualMachine::with_cells(size, self.growable, self.storage, prog);
+        if self.tape.len() > vm.size {
+            if !self.growable {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Initial tape has {} cells, but the machine has only {}.",
+                        self.tape.len(),
+                        vm.size
+                    ),
+                ));
+            }
+            vm.size = self.tape.len();
+            vm.cells.resize(vm.size);
+        }
+        if let MemoryModel::Grid(width) = self.memory {
+            if width == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "The width of the grid must be more than 0.",
+                ));
+            }
+            vm.size = vm.size.div_ceil(width) * width;
+            vm.cells.resize(vm.size);
+            if self.bidirectional {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "A 2d grid can not be bidirectional.",
+                ));
+            }
+            vm.memory = self.memory;
+        }
+        vm.bidirectional = self.bidirectional;
+        if self.head >= vm.size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Head position {} is outside of the tape of {} cells.",
+                    self.head, vm.size
+                ),
+            ));
+        }
+        for (index, value) in self.tape.iter().enumerate() {
+            vm.cells.set(index, C::from_byte(*value));
+        }
+        vm.head = self.head;
+        vm.set_eof_mode(self.eof);
+        vm.set_step_limit(self.step_limit);
+        vm.set_flush_policy(self.flush);
+        if let Some(capacity) = self.history {
+            vm.enable_history(capacity);
+        }
+        vm.io = self.io;
+        Ok(vm)
+    }
+}
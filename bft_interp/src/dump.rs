@@ -0,0 +1,308 @@
+//! Human readable dumps of the tape, and image dumps of the 2D grid.
+
+use crate::{CellKind, MemoryModel, Tape};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Number of cells we show on every line of the dump.
+const CELLS_PER_LINE: usize = 16;
+
+/// The different ways to print the tape.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DumpFormat {
+    /// Cell values as two digit hexadecimal numbers.
+    Hex,
+    /// Cell values as decimal numbers.
+    Decimal,
+    /// Hexadecimal values with the ASCII text side-by-side, like `xxd`.
+    Xxd,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(DumpFormat::Hex),
+            "dec" | "decimal" => Ok(DumpFormat::Decimal),
+            "xxd" | "ascii" => Ok(DumpFormat::Xxd),
+            _ => Err(format!(
+                "Unknown dump format {}, use one of hex, dec or xxd.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpFormat::Hex => write!(f, "hex"),
+            DumpFormat::Decimal => write!(f, "dec"),
+            DumpFormat::Xxd => write!(f, "xxd"),
+        }
+    }
+}
+
+/// The image formats for dumping the memory.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ImageFormat {
+    /// Grayscale PGM, one pixel per cell.
+    Pgm,
+    /// Color PPM, grayscale cells with the head in red.
+    Ppm,
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pgm" => Ok(ImageFormat::Pgm),
+            "ppm" => Ok(ImageFormat::Ppm),
+            _ => Err(format!("Unknown image format {}, use pgm or ppm.", s)),
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageFormat::Pgm => write!(f, "pgm"),
+            ImageFormat::Ppm => write!(f, "ppm"),
+        }
+    }
+}
+
+/// Writes the non-zero region of the `cells` to the writer.
+///
+/// The region always includes the `head`, and starts at a multiple of 16 so
+/// that the offsets line up. Offsets and the head are relative to the cell at
+/// index `origin`, so they are negative left of it. A grid is shown one row
+/// per line instead. The cell under the head is marked with a `*` instead of
+/// the space before the value. Values are padded to the width of the biggest
+/// value the cell type can hold.
+pub fn dump_cells<C: CellKind, W: Write>(
+    cells: &dyn Tape<C>,
+    head: usize,
+    origin: usize,
+    format: DumpFormat,
+    memory: MemoryModel,
+    w: &mut W,
+) -> std::io::Result<()> {
+    let per_line = match memory {
+        MemoryModel::Tape => CELLS_PER_LINE,
+        MemoryModel::Grid(width) => width,
+    };
+    let (first, last) = cells.used().unwrap_or((head, head));
+    let width = match format {
+        DumpFormat::Decimal => C::maximum().to_string().len(),
+        _ => format!("{:x}", C::maximum()).len(),
+    };
+    // Line up the rows with the origin
+    let shift = origin % per_line;
+    let first = first.min(head);
+    let start = if first < shift {
+        0
+    } else {
+        (first - shift) / per_line * per_line + shift
+    };
+    let end = last.max(head).min(cells.len().saturating_sub(1));
+
+    match memory {
+        MemoryModel::Tape => writeln!(
+            w,
+            "Tape (head at cell {}):",
+            head as isize - origin as isize
+        )?,
+        MemoryModel::Grid(width) => {
+            let (x, y) = memory.position(head);
+            writeln!(
+                w,
+                "Grid of {}x{} (head at column {}, row {}):",
+                width,
+                cells.len() / width,
+                x,
+                y
+            )?
+        }
+    }
+    let mut offset = start;
+    while offset <= end {
+        let next_line = offset + per_line - (offset + per_line - shift) % per_line;
+        let line_end = next_line.min(end + 1);
+        let line: Vec<C> = (offset..line_end).map(|index| cells.get(index)).collect();
+        let relative = offset as isize - origin as isize;
+        match format {
+            DumpFormat::Decimal => write!(w, "{:8}:", relative)?,
+            _ if relative < 0 => write!(w, "-{:07x}:", -relative)?,
+            _ => write!(w, "{:08x}:", relative)?,
+        }
+        for (index, value) in line.iter().enumerate() {
+            let marker = if offset + index == head { '*' } else { ' ' };
+            match format {
+                DumpFormat::Decimal => write!(w, "{}{:>width$}", marker, value, width = width)?,
+                _ => write!(w, "{}{:0width$x}", marker, value, width = width)?,
+            }
+        }
+        if format == DumpFormat::Xxd {
+            // Pad the short last line so that the text column lines up.
+            let padding = (per_line - line.len()) * (width + 1);
+            write!(w, "{:width$}  ", "", width = padding)?;
+            for value in line.iter() {
+                let byte = value.to_byte();
+                let ch =
+                    if C::from_byte(byte) == *value && (byte.is_ascii_graphic() || byte == b' ') {
+                        byte as char
+                    } else {
+                        '.'
+                    };
+                write!(w, "{}", ch)?;
+            }
+        }
+        writeln!(w)?;
+        offset = line_end;
+    }
+    Ok(())
+}
+
+/// Writes the cells as a binary PGM or PPM image, `width` cells per row.
+///
+/// Every cell is one pixel, its lowest byte is the brightness. A short last
+/// row is filled up with black pixels.
+pub fn write_image<C: CellKind, W: Write>(
+    cells: &dyn Tape<C>,
+    head: usize,
+    width: usize,
+    format: ImageFormat,
+    w: &mut W,
+) -> std::io::Result<()> {
+    let height = cells.len().div_ceil(width);
+    let magic = match format {
+        ImageFormat::Pgm => "P5",
+        ImageFormat::Ppm => "P6",
+    };
+    write!(w, "{}\n{} {}\n255\n", magic, width, height)?;
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for index in 0..width * height {
+        let value = if index < cells.len() {
+            cells.get(index).to_byte()
+        } else {
+            0
+        };
+        match format {
+            ImageFormat::Pgm => pixels.push(value),
+            ImageFormat::Ppm if index == head => pixels.extend_from_slice(&[255, 0, 0]),
+            ImageFormat::Ppm => pixels.extend_from_slice(&[value, value, value]),
+        }
+    }
+    w.write_all(&pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::{dump_cells, write_image, DumpFormat, ImageFormat};
+    use crate::{CellKind, MemoryModel};
+
+    fn dump<C: CellKind>(cells: &[C], head: usize, format: DumpFormat) -> String {
+        let mut out = Vec::new();
+        let cells = cells.to_vec();
+        dump_cells(&cells, head, 0, format, MemoryModel::Tape, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn hex_marks_head() {
+        let output = dump(&[7u8, 0, 255, 0, 0], 1, DumpFormat::Hex);
+        assert_eq!(output, "Tape (head at cell 1):\n00000000: 07*00 ff\n");
+    }
+
+    #[test]
+    fn decimal_skips_zero_lines() {
+        let mut cells = vec![0u8; 40];
+        cells[33] = 65;
+        let output = dump(&cells, 34, DumpFormat::Decimal);
+        assert_eq!(output, "Tape (head at cell 34):\n      32:   0  65*  0\n");
+    }
+
+    #[test]
+    fn xxd_has_ascii_column() {
+        let output = dump(&[72u8, 105, 0], 0, DumpFormat::Xxd);
+        let expected = format!("Tape (head at cell 0):\n00000000:*48 69{:42}  Hi\n", "");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn wide_cells() {
+        let output = dump(&[300u16, 65], 1, DumpFormat::Xxd);
+        let expected = format!("Tape (head at cell 1):\n00000000: 012c*0041{:70}  .A\n", "");
+        assert_eq!(output, expected);
+        let output = dump(&[300u16, 65], 0, DumpFormat::Decimal);
+        assert_eq!(output, "Tape (head at cell 0):\n       0:*  300    65\n");
+    }
+
+    #[test]
+    fn grid_rows() {
+        let mut out = Vec::new();
+        let cells = vec![0u8, 1, 2, 3, 4, 5, 0, 0, 0];
+        dump_cells(
+            &cells,
+            4,
+            0,
+            DumpFormat::Decimal,
+            MemoryModel::Grid(3),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Grid of 3x3 (head at column 1, row 1):\n       0:   0   1   2\n       3:   3*  4   5\n"
+        );
+    }
+
+    #[test]
+    fn images() {
+        let cells = vec![0u8, 255, 7];
+        let mut out = Vec::new();
+        write_image(&cells, 1, 2, ImageFormat::Pgm, &mut out).unwrap();
+        assert_eq!(out, b"P5\n2 2\n255\n\x00\xff\x07\x00");
+        let mut out = Vec::new();
+        write_image(&cells, 1, 3, ImageFormat::Ppm, &mut out).unwrap();
+        assert_eq!(out, b"P6\n3 1\n255\n\x00\x00\x00\xff\x00\x00\x07\x07\x07");
+    }
+
+    #[test]
+    fn negative_offsets() {
+        let mut cells = vec![0u8; 20];
+        cells[1] = 10;
+        let mut out = Vec::new();
+        dump_cells(&cells, 18, 17, DumpFormat::Hex, MemoryModel::Tape, &mut out).unwrap();
+        let mut short = Vec::new();
+        dump_cells(
+            &cells,
+            0,
+            17,
+            DumpFormat::Hex,
+            MemoryModel::Tape,
+            &mut short,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(short).unwrap(),
+            "Tape (head at cell -17):\n-0000011:*00\n-0000010: 0a\n"
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Tape (head at cell 1):\n-0000010: 0a 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n00000000: 00*00\n"
+        );
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("hex".parse::<DumpFormat>(), Ok(DumpFormat::Hex));
+        assert_eq!("dec".parse::<DumpFormat>(), Ok(DumpFormat::Decimal));
+        assert_eq!("xxd".parse::<DumpFormat>(), Ok(DumpFormat::Xxd));
+        assert!("oct".parse::<DumpFormat>().is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! Runs the part of a program before it reads any input ahead of time, and
+//! replaces it with code setting the machine directly to the state it ends in.
+
+use crate::{VMError, VirtualMachine};
+use bft_types::{Instruction, Opcode, Program};
+
+/// The state of the machine after the input-free prefix of a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix {
+    /// The cells up to the last nonzero one, or up to the head.
+    pub tape: Vec<u8>,
+    /// Position of the head.
+    pub head: usize,
+    /// What the prefix printed.
+    pub output: Vec<u8>,
+    /// Index of the first instruction left to run.
+    pub resume: usize,
+    /// Number of steps the prefix takes.
+    pub steps: u64,
+}
+
+impl Prefix {
+    /// Returns code without loops which prints the output and then sets the
+    /// tape and the head like the prefix, when it starts on a zero tape of
+    /// byte cells.
+    pub fn preamble(&self) -> String {
+        let mut code = String::new();
+        let mut cell = 0u8;
+        for byte in self.output.iter() {
+            add(&mut code, byte.wrapping_sub(cell));
+            code.push('.');
+            cell = *byte;
+        }
+        add(&mut code, 0u8.wrapping_sub(cell));
+
+        for (index, value) in self.tape.iter().enumerate() {
+            if index > 0 {
+                code.push('>');
+            }
+            add(&mut code, *value);
+        }
+        let last = self.tape.len().saturating_sub(1);
+        code.push_str(&"<".repeat(last - self.head));
+        code
+    }
+
+    /// Replaces the prefix of the program it was found in with the
+    /// [`Prefix::preamble`].
+    ///
+    /// The preamble points into a source named `<folded>`, the rest of the
+    /// program keeps its spans.
+    pub fn apply(&self, program: &Program) -> Program {
+        let preamble = self.preamble();
+        let mut sources = program.source_map().clone();
+        let file_id = sources.add("<folded>".to_string(), &preamble);
+        let mut ins: Vec<Instruction> = Program::new(String::new(), &preamble)
+            .instructions()
+            .iter()
+            .map(|ins| Instruction::new(ins.op, ins.span.in_file(file_id)))
+            .collect();
+        ins.extend_from_slice(&program.instructions()[self.resume..]);
+        program.with_instructions(sources, ins)
+    }
+}
+
+/// Appends the `+` or `-` changing a cell by `delta`, the shortest way.
+fn add(code: &mut String, delta: u8) {
+    if delta <= 128 {
+        code.push_str(&"+".repeat(delta as usize));
+    } else {
+        code.push_str(&"-".repeat(256 - delta as usize));
+    }
+}
+
+/// Number of instructions [`add`] appends for `delta`.
+fn add_len(delta: u8) -> usize {
+    (delta as usize).min(256 - delta as usize)
+}
+
+/// Tells if the [`Prefix::preamble`] for the machine as it is now would be at
+/// most `budget` instructions long.
+///
+/// `printing` is the length of the code printing the output so far, which
+/// leaves `last` in the cell. No cell past `reach` was ever changed.
+fn preamble_fits(
+    vm: &VirtualMachine,
+    printing: usize,
+    last: u8,
+    reach: usize,
+    budget: u64,
+) -> bool {
+    let head = vm.get_head();
+    let mut len = printing as u64 + add_len(0u8.wrapping_sub(last)) as u64;
+    let mut end = head;
+    for index in 0..=reach {
+        let value = vm.get_cell(index);
+        if value != 0 {
+            end = end.max(index);
+        }
+        len += add_len(value) as u64;
+        // With the moves to every cell up to the end, and back to the head
+        if len + (end + end - head) as u64 > budget {
+            return false;
+        }
+    }
+    true
+}
+
+/// Tells if we must stop before the opcode, because what it does is only
+/// known when the program runs, or it changes more than the tape and the head.
+fn stops(op: Opcode) -> bool {
+    matches!(
+        op,
+        Opcode::Input
+            | Opcode::DebugDump
+            | Opcode::EndProgram
+            | Opcode::SaveStorage
+            | Opcode::ProcedureStart
+            | Opcode::CallProcedure
+            | Opcode::MoveUp
+            | Opcode::MoveDown
+    )
+}
+
+/// Runs the program on a default machine with byte cells, until it reads
+/// input, runs an instruction we can not fold, fails, or takes `step_limit`
+/// steps.
+///
+/// The prefix ends at the last point where the machine was outside of every
+/// loop, so the rest of the program is whole, and where the preamble is
+/// neither longer nor slower than the part of the program it replaces. So
+/// folding never makes a program bigger or slower.
+pub fn run_prefix(program: &Program, step_limit: u64) -> Result<Prefix, VMError> {
+    program.validate()?;
+    // Number of loops and procedures around every instruction, and number of
+    // instructions before it
+    let mut depth = Vec::with_capacity(program.instructions().len());
+    let mut size = Vec::with_capacity(program.instructions().len() + 1);
+    let (mut level, mut count) = (0usize, 0u64);
+    for ins in program.instructions() {
+        depth.push(level);
+        size.push(count);
+        match ins.op {
+            Opcode::JumpForward | Opcode::ProcedureStart => level += 1,
+            Opcode::JumpBack | Opcode::ProcedureEnd => level -= 1,
+            _ => (),
+        }
+        if !matches!(ins.op, Opcode::Comment(_)) {
+            count += 1;
+        }
+    }
+    size.push(count);
+    let outside = |vm: &VirtualMachine| {
+        depth.get(vm.get_ip()).is_none_or(|level| *level == 0) && vm.get_call_depth() == 0
+    };
+
+    let mut vm = VirtualMachine::new(0, false, program.clone());
+    let mut output = Vec::new();
+    let (mut steps, mut last, mut reach) = (0, 0, 0);
+    // Length of the code printing the output so far
+    let mut printing = 0;
+    loop {
+        // Only the instructions outside of the loops are here, once each
+        if outside(&vm) {
+            let budget = steps.min(size[vm.get_ip()]);
+            let printed = output.last().copied().unwrap_or(0);
+            if preamble_fits(&vm, printing, printed, reach, budget) {
+                last = steps;
+            }
+        }
+        match vm.current_instruction() {
+            Some(ins) if steps < step_limit && !stops(ins.op) => (),
+            _ => break,
+        }
+        let (printed, before) = (output.len(), output.last().copied().unwrap_or(0));
+        if vm.step(&mut std::io::empty(), &mut output).is_err() {
+            break;
+        }
+        if output.len() > printed {
+            printing += add_len(output[printed].wrapping_sub(before)) + 1;
+        }
+        reach = reach.max(vm.get_head());
+        steps += 1;
+    }
+
+    // Run again up to the last point outside of the loops
+    let mut vm = VirtualMachine::new(0, false, program.clone());
+    let mut output = Vec::new();
+    for _ in 0..last {
+        vm.step(&mut std::io::empty(), &mut output)?;
+    }
+    let used = vm.get_tape().used().map_or(0, |(_, last)| last + 1);
+    let tape = vm.get_range(0..used.max(vm.get_head() + 1));
+    Ok(Prefix {
+        tape,
+        head: vm.get_head(),
+        output,
+        resume: vm.get_ip(),
+        steps: last,
+    })
+}
+
+/// Replaces the input-free prefix of the program, see [`run_prefix`], with
+/// code setting the machine directly to the state after it.
+///
+/// The new program prints the same and ends the same on a default machine
+/// with byte cells. A smaller tape may only fail in the original program,
+/// since the prefix already ran on the default one.
+pub fn fold_prefix(program: &Program, step_limit: u64) -> Result<Program, VMError> {
+    Ok(run_prefix(program, step_limit)?.apply(program))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fold::{fold_prefix, run_prefix};
+    use crate::VirtualMachine;
+    use bft_types::{Opcode, Program};
+
+    /// Runs the program, and returns its output or error.
+    fn run(program: Program, input: &[u8]) -> Result<Vec<u8>, String> {
+        let mut vm = VirtualMachine::new(0, false, program);
+        let mut output = Vec::new();
+        let mut input = input;
+        vm.interpret(&mut input, &mut output)
+            .map(|_| output)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Checks the folded program behaves like the program, and returns it.
+    fn check(code: &str, step_limit: u64, input: &[u8]) -> Program {
+        let program = Program::new("test.bf".to_string(), code);
+        let folded = fold_prefix(&program, step_limit).unwrap();
+        folded.validate().unwrap();
+        assert_eq!(run(folded.clone(), input), run(program, input), "{}", code);
+        folded
+    }
+
+    #[test]
+    fn folds_whole_program() {
+        let code = "+++++[>+++++<-]>[-]<++.";
+        let program = Program::new("test.bf".to_string(), code);
+        let prefix = run_prefix(&program, 1_000_000).unwrap();
+        assert_eq!(prefix.output, b"\x02");
+        assert_eq!((prefix.tape, prefix.resume), (vec![2], code.len()));
+        let folded = check(code, 1_000_000, b"");
+        assert_eq!(folded.to_string(), "++.--++");
+        assert_eq!(folded.source_map().name(1), Some("<folded>"));
+        assert_eq!(folded.instructions()[0].span.file_id, 1);
+    }
+
+    #[test]
+    fn never_bigger_or_slower() {
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        for code in [hello, "+><[[>.<+.]>]", "++++++++[>++++++++<-]>+.", ">>+<<"] {
+            let program = Program::new("test.bf".to_string(), code);
+            let folded = check(code, 1_000_000, b"");
+            assert!(folded.instructions().len() <= program.instructions().len());
+            let steps = |program| {
+                let mut vm = VirtualMachine::new(0, false, program);
+                vm.interpret(&mut &b""[..], &mut Vec::new()).unwrap();
+                vm.get_steps()
+            };
+            assert!(steps(folded) <= steps(program), "{}", code);
+        }
+    }
+
+    #[test]
+    fn stops_at_input() {
+        let code = "++++[>++++<-]>[<+>-]<,[.,]";
+        let program = Program::new("test.bf".to_string(), code);
+        let prefix = run_prefix(&program, 1_000_000).unwrap();
+        assert_eq!(
+            (prefix.tape, prefix.head, prefix.output, prefix.resume),
+            (vec![16], 0, Vec::new(), 21)
+        );
+        let folded = check(code, 1_000_000, b"abc\0");
+        // The rest keeps its spans
+        let rest = &folded.instructions()[folded.instructions().len() - 5..];
+        assert_eq!(rest[0].op, Opcode::Input);
+        assert_eq!((rest[0].span.file_id, rest[0].span.col), (0, 22));
+    }
+
+    #[test]
+    fn stops_outside_of_loops() {
+        let code = "++++++++[>++++++++<-]>+.";
+        // The step limit stops in the loop, so the prefix ends before it
+        let prefix = run_prefix(&Program::new("test.bf".to_string(), code), 20).unwrap();
+        assert_eq!((prefix.resume, prefix.steps), (8, 8));
+        check(code, 20, b"");
+        check(code, 0, b"");
+        // The failing instruction is left to run
+        let prefix = run_prefix(&Program::new("test.bf".to_string(), ">+<<+"), 100).unwrap();
+        assert_eq!((prefix.tape, prefix.resume), (vec![0, 1], 3));
+        check(">+<<+", 100, b"");
+    }
+}
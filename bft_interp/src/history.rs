@@ -0,0 +1,120 @@
+//! Undo log used for reverse (time-travel) debugging.
+
+use std::collections::VecDeque;
+
+/// One recorded step of the virtual machine.
+///
+/// It stores the state just before the instruction was executed, so that
+/// undoing the step only needs to put these values back.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Change<C = u8> {
+    /// Instruction pointer before the step.
+    pub ip: usize,
+    /// Head of the tape before the step.
+    pub head: usize,
+    /// The cell index and its old value, if the step wrote to a cell.
+    pub cell: Option<(usize, C)>,
+    /// The old value of the storage register, if the step wrote to it.
+    pub storage: Option<C>,
+    /// What the step did to the pbrain procedures, if anything.
+    pub procedure: Option<ProcedureChange>,
+}
+
+/// A change to the pbrain procedure table or call stack.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ProcedureChange {
+    /// `(` defined the procedure with this number, which had this old start
+    /// before.
+    Defined(usize, Option<usize>),
+    /// `:` pushed a return address on the call stack.
+    Called,
+    /// `)` popped this return address from the call stack.
+    Returned(usize),
+}
+
+/// A bounded undo log of [`Change`] entries.
+///
+/// When the log is full, the oldest entry is dropped, so the memory use never
+/// goes above `capacity` entries.
+#[derive(Debug)]
+pub struct History<C = u8> {
+    /// Maximum number of entries we keep.
+    capacity: usize,
+    /// The entries, oldest first.
+    changes: VecDeque<Change<C>>,
+}
+
+impl<C> History<C> {
+    /// Creates a new empty log which will keep at most `capacity` steps.
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity,
+            changes: VecDeque::new(),
+        }
+    }
+
+    /// Records a new step, dropping the oldest one if we are at the limit.
+    pub fn record(&mut self, change: Change<C>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.changes.len() == self.capacity {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Removes and returns the latest step.
+    pub fn pop(&mut self) -> Option<Change<C>> {
+        self.changes.pop_back()
+    }
+
+    /// Number of steps we can still go back.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Tells us if there is nothing to undo.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Moves all the recorded cell indices `by` cells to the right, after new
+    /// cells were added at the start of the tape.
+    pub(crate) fn shift(&mut self, by: usize) {
+        for change in self.changes.iter_mut() {
+            change.head += by;
+            if let Some((index, _)) = change.cell.as_mut() {
+                *index += by;
+            }
+        }
+    }
+
+    /// Maximum number of steps this log can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::{Change, History};
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut history: History = History::new(2);
+        for ip in 0..3 {
+            history.record(Change {
+                ip,
+                head: 0,
+                cell: None,
+                storage: None,
+                procedure: None,
+            });
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop().unwrap().ip, 2);
+        assert_eq!(history.pop().unwrap().ip, 1);
+        assert!(history.pop().is_none());
+    }
+}
@@ -1,32 +1,172 @@
 //! The interpreter for the language
 
-use bft_types::{Instruction, Program};
-use std::io::{Read, Write};
+// The errors are built with `io::Error::new(io::ErrorKind::Other, ..)`
+#![allow(clippy::io_other_error)]
+
+use bft_types::{Instruction, Opcode, Program};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufWriter, Read, Write};
+use std::ops::{BitAnd, BitOr, BitXor, Not, Range, Shl, Shr};
+use std::str::FromStr;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod builder;
+mod dump;
+mod fold;
+mod history;
+mod memory;
+mod tape;
+use builder::IoAdapters;
+pub use builder::VirtualMachineBuilder;
+pub use dump::{DumpFormat, ImageFormat};
+pub use fold::{fold_prefix, run_prefix, Prefix};
+pub use history::{Change, History, ProcedureChange};
+pub use memory::MemoryModel;
+pub use tape::{SparseTape, Storage, Tape};
 
 type VMError = std::io::Error;
 
+/// Number of cells on each side of the head the debug instruction `#` shows.
+const DEBUG_WINDOW: usize = 8;
+
+/// Maximum depth of nested pbrain procedure calls.
+const CALL_STACK_LIMIT: usize = 10_000;
+
+/// What the `,` instruction does when there is no more input.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum EofMode {
+    /// Leave the current cell as it is.
+    #[default]
+    Unchanged,
+    /// Set the current cell to 0.
+    Zero,
+    /// Set the current cell to its maximum value (-1), 255 for `u8` cells.
+    Max,
+}
+
+impl FromStr for EofMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(EofMode::Unchanged),
+            "zero" | "0" => Ok(EofMode::Zero),
+            "max" | "-1" | "255" => Ok(EofMode::Max),
+            _ => Err(format!(
+                "Unknown EOF mode {}, use one of unchanged, zero or max.",
+                s
+            )),
+        }
+    }
+}
+
+/// When the output of `.` is flushed to the writer.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum FlushPolicy {
+    /// After every byte.
+    Byte,
+    /// After every newline, and before reading input.
+    #[default]
+    Line,
+    /// Only before reading input and when the program ends.
+    Exit,
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "byte" => Ok(FlushPolicy::Byte),
+            "line" => Ok(FlushPolicy::Line),
+            "exit" => Ok(FlushPolicy::Exit),
+            _ => Err(format!(
+                "Unknown flush policy {}, use one of byte, line or exit.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FlushPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlushPolicy::Byte => write!(f, "byte"),
+            FlushPolicy::Line => write!(f, "line"),
+            FlushPolicy::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+impl fmt::Display for EofMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EofMode::Unchanged => write!(f, "unchanged"),
+            EofMode::Zero => write!(f, "zero"),
+            EofMode::Max => write!(f, "max"),
+        }
+    }
+}
+
 /// VirtualMachine holding the cells of the system
 ///
 /// It has the following members:
 ///
 /// - size for the current size of the machine
 /// - growable boolean value to tell us if the machine size is growable or not
-/// - cells is a vector holding the memory cells, all initialized with 0.
+/// - cells is the tape holding the memory cells, all initialized with 0.
+/// - memory is the shape of the cells, a tape or a 2D grid.
+/// - origin is the index of cell 0, a bidirectional tape grows to the left of it.
 /// - ip is the current Instruction Pointer.
+/// - history is the optional undo log for reverse debugging.
+/// - eof is what happens to the cell when `,` finds no more input.
+/// - steps and step_limit count the executed instructions, and optionally stop runaway programs.
+/// - flush is when the output of `.` gets flushed.
+/// - io holds the optional reader and writer for [`VirtualMachine::run`].
+/// - storage is the register of the Extended Type I instructions.
+/// - procedures and calls are the pbrain procedure table and call stack.
+///
+/// The cells are `u8` by default, use [`VirtualMachine::builder`] for wider cells.
 #[derive(Debug)]
-pub struct VirtualMachine {
+pub struct VirtualMachine<C = u8> {
     /// size of the machine
     size: usize,
     /// Boolean value to tells us if the machine is growable in size or not.
     growable: bool,
-    /// The vector holding the memory cells.
-    cells: Vec<u8>,
+    /// The storage holding the memory cells.
+    cells: Box<dyn Tape<C> + Send>,
+    /// If the cells are a tape or a grid.
+    memory: MemoryModel,
+    /// If the tape grows on demand in both directions.
+    bidirectional: bool,
+    /// Index of cell 0 in `cells`, more than 0 once the tape grew to the left.
+    origin: usize,
     /// Instruction pointer of the machine.
     ip: usize, // Instruction pointer
     /// head of the tape
     head: usize,
     /// The program to interpret
     prg: Program,
+    /// Undo log of the executed steps, if enabled.
+    history: Option<History<C>>,
+    /// What to do with the cell when the input has ended.
+    eof: EofMode,
+    /// Number of instructions executed so far.
+    steps: u64,
+    /// Maximum number of instructions we are allowed to execute.
+    step_limit: Option<u64>,
+    /// When to flush the output.
+    flush: FlushPolicy,
+    /// Reader and writer used by `run`.
+    io: IoAdapters,
+    /// Storage register used by `$`, `!` and the bitwise instructions.
+    storage: C,
+    /// Index of the `(` of every defined pbrain procedure, by its number.
+    procedures: HashMap<usize, usize>,
+    /// Return addresses of the pbrain procedure calls.
+    calls: Vec<usize>,
 }
 
 impl VirtualMachine {
@@ -36,6 +176,41 @@ impl VirtualMachine {
     /// If you pass `growable` as true, then the size of the system can grow dynamically.
     /// You can also pass the program which needs to be running on the virtual machine
     pub fn new(size: usize, growable: bool, prog: Program) -> Self {
+        VirtualMachine::with_cells(size, growable, Storage::Dense, prog)
+    }
+
+    /// Creates a new virtual machine with the tape pre-loaded from `tape`, and
+    /// the head placed at `head`.
+    ///
+    /// The `size` works like in [`VirtualMachine::new`]. If the `tape` has more
+    /// cells than `size`, the machine gets enough cells to hold all of it when it
+    /// is `growable`, otherwise we return an error. The `head` must point to a
+    /// cell inside the tape.
+    pub fn with_tape(
+        size: usize,
+        growable: bool,
+        prog: Program,
+        tape: &[u8],
+        head: usize,
+    ) -> Result<Self, VMError> {
+        VirtualMachine::builder()
+            .tape_size(size)
+            .growable(growable)
+            .tape(tape)
+            .head(head)
+            .build(prog)
+    }
+
+    /// Returns a builder to configure the virtual machine, like the tape size,
+    /// the cell width, the EOF mode, the step limit and the I/O adapters.
+    pub fn builder() -> VirtualMachineBuilder {
+        VirtualMachineBuilder::new()
+    }
+}
+
+impl<C: CellKind> VirtualMachine<C> {
+    /// Creates a new virtual machine with all cells set to 0, see [`VirtualMachine::new`].
+    fn with_cells(size: usize, growable: bool, storage: Storage, prog: Program) -> Self {
         let size = match size {
             0 => 30000,
             _ => size,
@@ -44,10 +219,22 @@ impl VirtualMachine {
         VirtualMachine {
             size,
             growable,
-            cells: vec![0u8; size],
+            cells: storage.create(size),
+            memory: MemoryModel::Tape,
+            bidirectional: false,
+            origin: 0,
             ip: 0,
             head: 0,
             prg: prog,
+            history: None,
+            eof: EofMode::default(),
+            steps: 0,
+            step_limit: None,
+            flush: FlushPolicy::default(),
+            io: IoAdapters::default(),
+            storage: C::default(),
+            procedures: HashMap::new(),
+            calls: Vec::new(),
         }
     }
 
@@ -56,14 +243,131 @@ impl VirtualMachine {
         self.growable
     }
 
-    /// To borrow the cells
-    pub fn get_cells(&self) -> &[u8] {
-        &self.cells[..]
+    /// To borrow all the cells
+    ///
+    /// On a bidirectional tape the cells left of the origin come first, see
+    /// [`VirtualMachine::get_origin`].
+    ///
+    /// # Panics
+    ///
+    /// With [`Storage::Sparse`], which has no slice of the cells, use
+    /// [`VirtualMachine::get_range`] or [`VirtualMachine::get_cell`] instead.
+    pub fn get_cells(&self) -> &[C] {
+        self.cells
+            .as_slice()
+            .expect("Only the dense storage has a slice of the cells")
+    }
+
+    /// Returns a copy of the cells in `range`, with any storage.
+    pub fn get_range(&self, range: Range<usize>) -> Vec<C> {
+        range.map(|index| self.cells.get(index)).collect()
+    }
+
+    /// Returns the value of one cell.
+    pub fn get_cell(&self, index: usize) -> C {
+        self.cells.get(index)
+    }
+
+    /// To borrow the storage of the cells
+    pub fn get_tape(&self) -> &dyn Tape<C> {
+        self.cells.as_ref()
+    }
+
+    /// Tells us if the tape grows in both directions.
+    pub fn is_bidirectional(&self) -> bool {
+        self.bidirectional
+    }
+
+    /// Returns the index of cell 0 in [`VirtualMachine::get_cells`].
+    pub fn get_origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Returns the position of the head relative to cell 0, negative left of it.
+    pub fn get_position(&self) -> isize {
+        self.head as isize - self.origin as isize
+    }
+
+    /// Returns the shape of the memory.
+    pub fn get_memory_model(&self) -> MemoryModel {
+        self.memory
+    }
+
+    /// Writes the non-zero region of the tape to the writer, marking the head.
+    pub fn dump_tape<W: Write>(&self, w: &mut W, format: DumpFormat) -> Result<(), VMError> {
+        dump::dump_cells(
+            self.cells.as_ref(),
+            self.head,
+            self.origin,
+            format,
+            self.memory,
+            w,
+        )
+    }
+
+    /// Writes all the cells as an image, one pixel per cell.
+    ///
+    /// A grid keeps its width, a tape is cut into rows of 16 cells.
+    pub fn dump_image<W: Write>(&self, w: &mut W, format: ImageFormat) -> Result<(), VMError> {
+        let width = match self.memory {
+            MemoryModel::Tape => 16,
+            MemoryModel::Grid(width) => width,
+        };
+        dump::write_image(self.cells.as_ref(), self.head, width, format, w)
+    }
+
+    /// Sets what the `,` instruction does at the end of the input.
+    pub fn set_eof_mode(&mut self, eof: EofMode) {
+        self.eof = eof;
+    }
+
+    /// Sets the maximum number of instructions the machine may execute, `None`
+    /// means no limit.
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    /// Sets when the output of `.` is flushed.
+    pub fn set_flush_policy(&mut self, flush: FlushPolicy) {
+        self.flush = flush;
+    }
+
+    /// Returns the number of instructions executed so far.
+    pub fn get_steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Returns the value of the Extended Type I storage register.
+    pub fn get_storage(&self) -> C {
+        self.storage
+    }
+
+    /// Returns the depth of the pbrain procedure calls.
+    pub fn get_call_depth(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// Returns the current instruction pointer.
+    pub fn get_ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Returns the current head position on the tape.
+    pub fn get_head(&self) -> usize {
+        self.head
+    }
+
+    /// Returns the instruction at the current instruction pointer, if the
+    /// program has not finished yet.
+    pub fn current_instruction(&self) -> Option<Instruction> {
+        self.prg.instructions().get(self.ip).copied()
     }
 
     /// Executes the given program structure
+    ///
+    /// The output is buffered and flushed following the flush policy, and
+    /// always at the end, also when the program fails.
     pub fn interpret<R, W>(&mut self, input: &mut R, output: &mut W) -> Result<(), VMError>
-    //pub fn interpret(&mut self) -> Result<(), VMError>
     where
         R: Read,
         W: Write,
@@ -74,51 +378,356 @@ impl VirtualMachine {
                 "Program already executed.",
             ));
         }
-        let length = self.prg.instructions().len();
 
-        loop {
-            if self.ip >= length {
-                break;
-            }
-            let ins = self.prg.instructions()[self.ip];
-            //dbg!(ins);
-            match ins {
-                Instruction::IncrementDP(_, _) => {
-                    self.head += 1;
-                    self.ip += 1
-                }
-                Instruction::DecrementDP(_, _) => {
-                    self.head -= 1;
-                    self.ip += 1
-                }
-                Instruction::IncrementByte(_, _) => {
-                    self.cells[self.head].wrapping_increment();
-                    self.ip += 1
+        let mut output = BufWriter::new(output);
+        let res = loop {
+            match self.step(input, &mut output) {
+                Ok(true) => (),
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        // The first error wins, a failed flush only matters after a good run
+        let flushed = output.flush();
+        res.and(flushed)
+    }
+
+    /// Executes the program with the reader and writer given to the builder.
+    ///
+    /// Without a reader `,` reads the input written after the `!` separator in
+    /// the source, or sees the end of the input. Without a writer the output
+    /// of `.` is thrown away.
+    pub fn run(&mut self) -> Result<(), VMError> {
+        let embedded = self.prg.input().unwrap_or_default().as_bytes().to_vec();
+        let mut input = self
+            .io
+            .input
+            .take()
+            .unwrap_or_else(|| Box::new(std::io::Cursor::new(embedded)));
+        let mut output = self
+            .io
+            .output
+            .take()
+            .unwrap_or_else(|| Box::new(std::io::sink()));
+        let res = self.interpret(&mut input, &mut output);
+        self.io.input = Some(input);
+        self.io.output = Some(output);
+        res
+    }
+
+    /// Executes only the instruction at the current instruction pointer.
+    ///
+    /// Returns `Ok(false)` when there is nothing left to execute, so a debugger
+    /// can call it in a loop. If the history is enabled, the step gets recorded
+    /// in the undo log.
+    pub fn step<R, W>(&mut self, input: &mut R, output: &mut W) -> Result<bool, VMError>
+    where
+        R: Read,
+        W: Write,
+    {
+        let ins = match self.current_instruction() {
+            Some(ins) => ins,
+            None => return Ok(false),
+        };
+        if let Some(limit) = self.step_limit {
+            if self.steps >= limit {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Step limit of {} reached.", limit),
+                ));
+            }
+        }
+        self.steps += 1;
+        let change = if self.history.is_some() {
+            let cell = match ins.op {
+                Opcode::IncrementByte
+                | Opcode::DecrementByte
+                | Opcode::Input
+                | Opcode::LoadStorage
+                | Opcode::ShiftRight
+                | Opcode::ShiftLeft
+                | Opcode::BitNot
+                | Opcode::BitXor
+                | Opcode::BitAnd
+                | Opcode::BitOr => Some((self.head, self.cells.get(self.head))),
+                _ => None,
+            };
+            let storage = match ins.op {
+                Opcode::SaveStorage => Some(self.storage),
+                _ => None,
+            };
+            let procedure = match ins.op {
+                Opcode::ProcedureStart => {
+                    let id = self.cells.get(self.head).to_index();
+                    Some(ProcedureChange::Defined(
+                        id,
+                        self.procedures.get(&id).copied(),
+                    ))
                 }
-                Instruction::DecrementByte(_, _) => {
-                    self.cells[self.head].wrapping_decrement();
-                    self.ip += 1
+                Opcode::CallProcedure => Some(ProcedureChange::Called),
+                Opcode::ProcedureEnd => {
+                    self.calls.last().map(|ret| ProcedureChange::Returned(*ret))
                 }
-                Instruction::Output(_, _) => {
-                    let _ = self.output(output);
+                _ => None,
+            };
+            Some(Change {
+                ip: self.ip,
+                head: self.head,
+                cell,
+                storage,
+                procedure,
+            })
+        } else {
+            None
+        };
+        //dbg!(ins);
+        let span = ins.span;
+        match ins.op {
+            Opcode::IncrementDP => {
+                self.move_head_right()?;
+            }
+            Opcode::DecrementDP => {
+                self.move_head_left()?;
+            }
+            Opcode::MoveUp => {
+                self.move_head_up()?;
+            }
+            Opcode::MoveDown => {
+                self.move_head_down()?;
+            }
+            Opcode::IncrementByte => {
+                let mut value = self.cells.get(self.head);
+                value.wrapping_increment();
+                self.cells.set(self.head, value);
+                self.ip += 1
+            }
+            Opcode::DecrementByte => {
+                let mut value = self.cells.get(self.head);
+                value.wrapping_decrement();
+                self.cells.set(self.head, value);
+                self.ip += 1
+            }
+            Opcode::Output => {
+                self.output(output)
+                    .map_err(|e| io_error(e, "Output", &self.prg.describe(&span)))?;
+            }
+            Opcode::Input => {
+                // Show any prompt before waiting for the input
+                output
+                    .flush()
+                    .map_err(|e| io_error(e, "Output", &self.prg.describe(&span)))?;
+                self.input(input)
+                    .map_err(|e| io_error(e, "Input", &self.prg.describe(&span)))?;
+            }
+            Opcode::JumpForward => {
+                self.start_loop()?;
+            }
+            Opcode::JumpBack => {
+                self.end_loop()?;
+            }
+            Opcode::Comment(_) => self.ip += 1,
+            Opcode::EndProgram => self.ip = self.prg.instructions().len(),
+            Opcode::SaveStorage => {
+                self.storage = self.cells.get(self.head);
+                self.ip += 1
+            }
+            Opcode::LoadStorage => {
+                self.cells.set(self.head, self.storage);
+                self.ip += 1
+            }
+            Opcode::ShiftRight => {
+                self.cells.set(self.head, self.cells.get(self.head) >> 1);
+                self.ip += 1
+            }
+            Opcode::ShiftLeft => {
+                self.cells.set(self.head, self.cells.get(self.head) << 1);
+                self.ip += 1
+            }
+            Opcode::BitNot => {
+                self.cells.set(self.head, !self.cells.get(self.head));
+                self.ip += 1
+            }
+            Opcode::BitXor => {
+                self.cells
+                    .set(self.head, self.cells.get(self.head) ^ self.storage);
+                self.ip += 1
+            }
+            Opcode::BitAnd => {
+                self.cells
+                    .set(self.head, self.cells.get(self.head) & self.storage);
+                self.ip += 1
+            }
+            Opcode::BitOr => {
+                self.cells
+                    .set(self.head, self.cells.get(self.head) | self.storage);
+                self.ip += 1
+            }
+            Opcode::DebugDump => {
+                let state = self.debug_state();
+                match self.io.debug.as_mut() {
+                    Some(w) => writeln!(w, "{}", state)?,
+                    None => eprintln!("{}", state),
                 }
-                Instruction::Input(_, _) => {
-                    let _ = self.input(input);
+                self.ip += 1
+            }
+            Opcode::ProcedureStart => {
+                self.define_procedure()?;
+            }
+            Opcode::ProcedureEnd => match self.calls.pop() {
+                Some(ret) => self.ip = ret,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!(
+                            "Return without a procedure call at {}.",
+                            self.prg.describe(&span)
+                        ),
+                    ));
                 }
-                Instruction::JumpForward(_, _) => {
-                    self.start_loop()?;
+            },
+            Opcode::CallProcedure => {
+                self.call_procedure()?;
+            }
+        }
+        // Only the steps which did not fail can be undone
+        if let (Some(history), Some(change)) = (self.history.as_mut(), change) {
+            history.record(change);
+        }
+        Ok(true)
+    }
+
+    /// Describes the current state of the machine, as printed by `#`.
+    ///
+    /// Shows the head, the instruction pointer with its position in the source,
+    /// and the cells around the head, with the head cell in brackets.
+    pub fn debug_state(&self) -> String {
+        let origin = self.origin as isize;
+        let start = self.head.saturating_sub(DEBUG_WINDOW);
+        let end = (self.head + DEBUG_WINDOW + 1).min(self.cells.len());
+        let cells: Vec<String> = (start..end)
+            .map(|index| {
+                if index == self.head {
+                    format!("[{}]", self.cells.get(index))
+                } else {
+                    self.cells.get(index).to_string()
                 }
-                Instruction::JumpBack(_, _) => {
-                    self.end_loop()?;
+            })
+            .collect();
+        let position = match self.current_instruction() {
+            Some(ins) => self.prg.source_map().location(&ins.span),
+            None => self.prg.source_file().to_string(),
+        };
+        let head = match self.memory {
+            MemoryModel::Tape => self.get_position().to_string(),
+            MemoryModel::Grid(_) => {
+                let (x, y) = self.memory.position(self.head);
+                format!("{} (column {}, row {})", self.head, x, y)
+            }
+        };
+        format!(
+            "head: {} ip: {} at {} cells {}..{}: {}",
+            head,
+            self.ip,
+            position,
+            start as isize - origin,
+            end as isize - origin,
+            cells.join(" ")
+        )
+    }
+
+    /// Starts recording every step in an undo log, keeping at most `capacity` steps.
+    ///
+    /// Any previously recorded history is dropped.
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    /// Stops recording and drops the undo log.
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    /// To borrow the undo log
+    pub fn get_history(&self) -> Option<&History<C>> {
+        self.history.as_ref()
+    }
+
+    /// Undoes the last executed step.
+    ///
+    /// Restores the instruction pointer, the head and the cell value from the
+    /// undo log. Any output already written or input already consumed can not be
+    /// taken back. Returns `Ok(false)` when there is nothing left to undo.
+    pub fn step_back(&mut self) -> Result<bool, VMError> {
+        Ok(self.undo()?.is_some())
+    }
+
+    /// Goes back in time till the last step which changed the given cell.
+    ///
+    /// The cell is relative to cell 0, like [`VirtualMachine::get_position`],
+    /// so it can be negative on a bidirectional tape. After this the
+    /// instruction pointer points to the instruction which wrote the cell, and
+    /// the cell holds the value it had before that. Returns `Ok(false)` if the
+    /// undo log ran out before finding such a step.
+    pub fn reverse_continue(&mut self, cell: isize) -> Result<bool, VMError> {
+        // Undoing never shrinks the tape, so the origin stays put
+        let target = self.origin as isize + cell;
+        while let Some(change) = self.undo()? {
+            if let Some((index, _)) = change.cell {
+                if index as isize == target {
+                    return Ok(true);
                 }
-                Instruction::Comment(_, _, _) => self.ip += 1,
             }
         }
-        Ok(())
+        Ok(false)
+    }
+
+    /// Pops the latest step from the undo log and restores the machine state
+    /// from it.
+    fn undo(&mut self) -> Result<Option<Change<C>>, VMError> {
+        let history = match self.history.as_mut() {
+            Some(history) => history,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "History is not enabled.",
+                ));
+            }
+        };
+        let change = match history.pop() {
+            Some(change) => change,
+            None => return Ok(None),
+        };
+        self.ip = change.ip;
+        self.head = change.head;
+        if let Some((index, value)) = change.cell {
+            self.cells.set(index, value);
+        }
+        if let Some(value) = change.storage {
+            self.storage = value;
+        }
+        match change.procedure {
+            Some(ProcedureChange::Defined(id, Some(start))) => {
+                self.procedures.insert(id, start);
+            }
+            Some(ProcedureChange::Defined(id, None)) => {
+                self.procedures.remove(&id);
+            }
+            Some(ProcedureChange::Called) => {
+                self.calls.pop();
+            }
+            Some(ProcedureChange::Returned(ret)) => self.calls.push(ret),
+            None => (),
+        }
+        Ok(Some(change))
     }
 
     /// Moves the head to left
+    ///
+    /// A bidirectional tape gets more cells on the left.
     pub fn move_head_left(&mut self) -> Result<usize, VMError> {
+        if self.head == 0 && self.bidirectional {
+            self.grow_left();
+        }
         if self.head == 0 {
             // Means already at the beginning
             return Err(std::io::Error::new(
@@ -126,6 +735,14 @@ impl VirtualMachine {
                 "Already at the beginning of the tape.",
             ));
         }
+        if let MemoryModel::Grid(width) = self.memory {
+            if self.head.is_multiple_of(width) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    "Already at the left edge of the grid.",
+                ));
+            }
+        }
         self.head -= 1;
 
         // Increase IP
@@ -134,45 +751,135 @@ impl VirtualMachine {
     }
 
     /// Moves the head to right
+    ///
+    /// A growable or bidirectional tape gets one more cell at the end.
     pub fn move_head_right(&mut self) -> Result<usize, VMError> {
+        if let MemoryModel::Grid(width) = self.memory {
+            if self.head % width == width - 1 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    "Already at the right edge of the grid.",
+                ));
+            }
+        }
         if self.head == (self.cells.len() - 1) {
-            // Means already at the end
+            if !self.growable && !self.bidirectional {
+                // Means already at the end
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    "Already at the end of the tape.",
+                ));
+            }
+            self.grow(1);
+        }
+        self.head += 1;
+        // Increase IP
+        self.ip += 1;
+        Ok(self.ip)
+    }
+
+    /// Moves the head one row up in the 2D grid
+    pub fn move_head_up(&mut self) -> Result<usize, VMError> {
+        let width = self.grid_width()?;
+        if self.head < width {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::AddrNotAvailable,
-                "Already at the end of the tape.",
+                "Already at the top of the grid.",
             ));
         }
-        self.head += 1;
-        // Increase IP
+        self.head -= width;
+        self.ip += 1;
+        Ok(self.ip)
+    }
+
+    /// Moves the head one row down in the 2D grid
+    ///
+    /// A growable grid gets one more row at the bottom.
+    pub fn move_head_down(&mut self) -> Result<usize, VMError> {
+        let width = self.grid_width()?;
+        if self.head + width >= self.cells.len() {
+            if !self.growable {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    "Already at the bottom of the grid.",
+                ));
+            }
+            self.grow(width);
+        }
+        self.head += width;
         self.ip += 1;
         Ok(self.ip)
     }
 
+    /// Returns the width of the grid, or an error for a tape.
+    fn grid_width(&self) -> Result<usize, VMError> {
+        match self.memory {
+            MemoryModel::Grid(width) => Ok(width),
+            MemoryModel::Tape => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Moving up and down needs the 2d memory model.",
+            )),
+        }
+    }
+
+    /// Adds more cells at the end of the memory.
+    fn grow(&mut self, cells: usize) {
+        self.size += cells;
+        self.cells.resize(self.size);
+    }
+
+    /// Doubles the tape by adding cells before the current first cell.
+    ///
+    /// All the indices move to the right, so the origin, the head and the undo
+    /// log are moved along.
+    fn grow_left(&mut self) {
+        let extra = self.cells.grow_front(self.cells.len().max(16));
+        self.size += extra;
+        self.origin += extra;
+        self.head += extra;
+        if let Some(history) = self.history.as_mut() {
+            history.shift(extra);
+        }
+    }
+
     /// Reads into current head of the tape
     ///
-    /// Needs a Reader reference to read from.
+    /// Needs a Reader reference to read from. At the end of the input the cell
+    /// is set as per the EOF mode of the machine.
     pub fn input<R>(&mut self, r: &mut R) -> Result<usize, VMError>
     where
         R: Read,
     {
         // Reading only 1 byte at a time
         let mut buf = vec![0u8; 1];
-        r.read_exact(&mut buf)?;
-        // Now assign the value
-        self.cells[self.head] = buf[0];
+        match r.read_exact(&mut buf) {
+            // Now assign the value
+            Ok(()) => self.cells.set(self.head, C::from_byte(buf[0])),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => match self.eof {
+                EofMode::Unchanged => (),
+                EofMode::Zero => self.cells.set(self.head, C::default()),
+                EofMode::Max => self.cells.set(self.head, C::maximum()),
+            },
+            Err(e) => return Err(e),
+        }
         // Increase IP
         self.ip += 1;
         Ok(self.ip)
     }
 
-    /// Writes one byte from the head of the tape to the Write
+    /// Writes one byte from the head of the tape to the Write, and flushes it
+    /// if the flush policy says so
     pub fn output<W>(&mut self, w: &mut W) -> Result<usize, VMError>
     where
         W: Write,
     {
-        //dbg!(self.cells[self.head]);
-        w.write_all(&[self.cells[self.head]])?;
-        w.flush().unwrap();
+        let byte = self.cells.get(self.head).to_byte();
+        w.write_all(&[byte])?;
+        match self.flush {
+            FlushPolicy::Byte => w.flush()?,
+            FlushPolicy::Line if byte == b'\n' => w.flush()?,
+            _ => (),
+        }
         // Increase IP
         self.ip += 1;
         Ok(self.ip)
@@ -182,17 +889,17 @@ impl VirtualMachine {
     pub fn start_loop(&mut self) -> Result<usize, VMError> {
         let mut stack: Vec<usize> = vec![self.ip];
         // Check if head is 0
-        if self.cells[self.head] == 0 {
+        if self.cells.get(self.head) == C::default() {
             let ins = self.prg.instructions();
             loop {
                 self.ip += 1;
                 // Now check for the matching closing ]
-                match ins[self.ip] {
-                    Instruction::JumpForward(_, _) => {
+                match ins[self.ip].op {
+                    Opcode::JumpForward => {
                         // Push the IP value
                         stack.push(self.ip);
                     }
-                    Instruction::JumpBack(_, _) => {
+                    Opcode::JumpBack => {
                         // Now we have to pop and see if we are in the right place
                         stack.pop();
                         // If the stack is empty means we are at the right place.
@@ -211,21 +918,87 @@ impl VirtualMachine {
         }
         Ok(self.ip)
     }
+    /// Call this when you see a pbrain `(`.
+    ///
+    /// Defines the procedure numbered by the current cell and jumps over its
+    /// body, to the instruction after the matching `)`.
+    pub fn define_procedure(&mut self) -> Result<usize, VMError> {
+        self.procedures
+            .insert(self.cells.get(self.head).to_index(), self.ip);
+        let ins = self.prg.instructions();
+        let mut depth = 0;
+        loop {
+            match ins.get(self.ip).map(|ins| ins.op) {
+                Some(Opcode::ProcedureStart) => depth += 1,
+                Some(Opcode::ProcedureEnd) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                Some(_) => (),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Procedure without a closing parenthesis.",
+                    ));
+                }
+            }
+            self.ip += 1;
+        }
+        self.ip += 1;
+        Ok(self.ip)
+    }
+
+    /// Call this when you see a pbrain `:`.
+    ///
+    /// Jumps into the body of the procedure numbered by the current cell, and
+    /// remembers where to return to.
+    pub fn call_procedure(&mut self) -> Result<usize, VMError> {
+        let at = || match self.current_instruction() {
+            Some(ins) => self.prg.describe(&ins.span),
+            None => self.prg.source_file().to_string(),
+        };
+        let id = self.cells.get(self.head).to_index();
+        let start = match self.procedures.get(&id) {
+            Some(start) => *start,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Procedure {} is not defined at {}.", id, at()),
+                ));
+            }
+        };
+        if self.calls.len() >= CALL_STACK_LIMIT {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "Call stack overflow at {}, more than {} nested calls.",
+                    at(),
+                    CALL_STACK_LIMIT
+                ),
+            ));
+        }
+        self.calls.push(self.ip + 1);
+        self.ip = start + 1;
+        Ok(self.ip)
+    }
+
     /// Call this when you see a jump back
     pub fn end_loop(&mut self) -> Result<usize, VMError> {
         let mut stack: Vec<usize> = vec![self.ip];
         // Check if head is 0
-        if self.cells[self.head] != 0 {
+        if self.cells.get(self.head) != C::default() {
             let ins = self.prg.instructions();
             loop {
                 self.ip -= 1;
                 // Now check for the matching closing ]
-                match ins[self.ip] {
-                    Instruction::JumpBack(_, _) => {
+                match ins[self.ip].op {
+                    Opcode::JumpBack => {
                         // Push the IP value
                         stack.push(self.ip);
                     }
-                    Instruction::JumpForward(_, _) => {
+                    Opcode::JumpForward => {
                         // Now we have to pop and see if we are in the right place
                         stack.pop();
                         // If the stack is empty means we are at the right place.
@@ -247,28 +1020,109 @@ impl VirtualMachine {
 }
 
 /// Our trait to handle Cell data
-pub trait CellKind {
+///
+/// The machine reads and writes bytes, so every cell type also has to convert
+/// from and to a byte. The bitwise operators are for the Extended Type I
+/// instructions.
+pub trait CellKind:
+    'static
+    + Send
+    + Copy
+    + Default
+    + PartialEq
+    + fmt::Debug
+    + fmt::Display
+    + fmt::LowerHex
+    + Not<Output = Self>
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + BitXor<Output = Self>
+    + Shl<u32, Output = Self>
+    + Shr<u32, Output = Self>
+{
     /// We can increase a cell value
     fn wrapping_increment(&mut self);
     /// We can decrease a cell value
     fn wrapping_decrement(&mut self);
+    /// Creates a cell value from a byte of input
+    fn from_byte(byte: u8) -> Self;
+    /// The lowest byte of the cell value, for output
+    fn to_byte(self) -> u8;
+    /// The biggest value a cell can hold
+    fn maximum() -> Self;
+    /// The cell value as a number, for the pbrain procedure table
+    fn to_index(self) -> usize;
 }
 
-/// Implementing for u8
-impl CellKind for u8 {
-    fn wrapping_increment(&mut self) {
-        *self = self.wrapping_add(1);
-    }
-    fn wrapping_decrement(&mut self) {
-        *self = self.wrapping_sub(1);
-    }
+/// Adds the position of the `.` or `,` instruction to an I/O error, keeping
+/// its kind so that callers can still match on it.
+pub(crate) fn io_error(e: std::io::Error, what: &str, at: &str) -> VMError {
+    std::io::Error::new(
+        e.kind(),
+        format!("{} failed at {} ({:?}): {}", what, at, e.kind(), e),
+    )
+}
+
+/// Implements CellKind for the unsigned integer types
+macro_rules! impl_cell_kind {
+    ($($t:ty),*) => {
+        $(
+            impl CellKind for $t {
+                fn wrapping_increment(&mut self) {
+                    *self = self.wrapping_add(1);
+                }
+                fn wrapping_decrement(&mut self) {
+                    *self = self.wrapping_sub(1);
+                }
+                fn from_byte(byte: u8) -> Self {
+                    byte as $t
+                }
+                fn to_byte(self) -> u8 {
+                    self as u8
+                }
+                fn maximum() -> Self {
+                    <$t>::MAX
+                }
+                fn to_index(self) -> usize {
+                    self as usize
+                }
+            }
+        )*
+    };
+}
+
+impl_cell_kind!(u8, u16, u32);
+
+/// Runs the brainfuck `code` with the given `input` on a default virtual
+/// machine, and returns everything the program wrote.
+///
+/// # Example
+///
+/// ```
+/// let output = bft_interp::run_str(",[.[-],]", b"echo")?;
+/// assert_eq!(output, b"echo");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn run_str(code: &str, input: &[u8]) -> Result<Vec<u8>, VMError> {
+    let program = Program::new("<string>".to_string(), code);
+    program.validate()?;
+    let mut vm = VirtualMachine::new(0, false, program);
+    let mut input = input;
+    let mut output = Vec::new();
+    vm.interpret(&mut input, &mut output)?;
+    Ok(output)
 }
 
 #[cfg(test)]
 mod tests {
-    use bft_types::Program;
+    use bft_types::{Dialect, Extension, Instruction, Opcode, Program, Span};
 
+    use crate::run_str;
     use crate::CellKind;
+    use crate::EofMode;
+    use crate::FlushPolicy;
+    use crate::MemoryModel;
+    use crate::Storage;
     use crate::VirtualMachine;
     use std::io::Cursor;
 
@@ -292,7 +1146,7 @@ mod tests {
     fn t_jump_back() {
         let p = get_small_program();
         let mut vm = VirtualMachine::new(3, false, p);
-        vm.cells = vec![1, 2, 3];
+        vm.cells = Box::new(vec![1, 2, 3]);
         // now test
         vm.ip = 15;
         let ip = vm.end_loop().unwrap();
@@ -357,27 +1211,616 @@ mod tests {
         assert_eq!(res.to_string(), expected.to_string());
     }
 
+    #[test]
+    fn step_back_restores_state() {
+        let p = get_small_program();
+        let mut vm = VirtualMachine::new(3, false, p);
+        vm.enable_history(100);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        // Run "++>"
+        for _ in 0..3 {
+            assert!(vm.step(&mut input, &mut output).unwrap());
+        }
+        assert_eq!(vm.get_cells()[0], 2);
+        assert_eq!(vm.get_head(), 1);
+
+        assert!(vm.step_back().unwrap());
+        assert_eq!(vm.get_head(), 0);
+        assert_eq!(vm.get_ip(), 2);
+        assert!(vm.step_back().unwrap());
+        assert_eq!(vm.get_cells()[0], 1);
+        assert!(vm.step_back().unwrap());
+        assert_eq!(vm.get_cells()[0], 0);
+        assert_eq!(vm.get_ip(), 0);
+        assert!(!vm.step_back().unwrap());
+    }
+
+    #[test]
+    fn reverse_continue_to_cell_change() {
+        let p = get_small_program();
+        let mut vm = VirtualMachine::new(3, false, p);
+        vm.enable_history(1000);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        vm.interpret(&mut input, &mut output).unwrap();
+        assert_eq!(vm.get_cells(), &[7, 0, 0]);
+
+        // The last write to cell 0 is the `+` inside the loop.
+        assert!(vm.reverse_continue(0).unwrap());
+        assert_eq!(vm.get_ip(), 12);
+        assert_eq!(vm.get_cells()[0], 6);
+        let ins = vm.current_instruction().unwrap();
+        assert_eq!(ins.op, Opcode::IncrementByte);
+        assert_eq!(ins.position(), (1, 13));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let p = get_small_program();
+        let mut vm = VirtualMachine::new(3, false, p);
+        vm.enable_history(5);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        vm.interpret(&mut input, &mut output).unwrap();
+        assert_eq!(vm.get_history().unwrap().len(), 5);
+
+        let mut count = 0;
+        while vm.step_back().unwrap() {
+            count += 1;
+        }
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn step_back_without_history() {
+        let p = get_small_program();
+        let mut vm = VirtualMachine::new(3, false, p);
+        assert!(vm.step_back().is_err());
+    }
+
+    #[test]
+    fn start_with_tape() {
+        let p = get_small_program();
+        let vm = VirtualMachine::with_tape(5, false, p, &[1, 2, 3], 2).unwrap();
+        assert_eq!(vm.get_cells(), &[1, 2, 3, 0, 0]);
+        assert_eq!(vm.get_head(), 2);
+    }
+
+    #[test]
+    fn start_with_long_tape() {
+        let p = get_small_program();
+        let res = VirtualMachine::with_tape(2, false, p, &[1, 2, 3], 0);
+        assert_eq!(
+            res.err().unwrap().to_string(),
+            "Initial tape has 3 cells, but the machine has only 2."
+        );
+
+        let p = get_small_program();
+        let vm = VirtualMachine::with_tape(2, true, p, &[1, 2, 3], 0).unwrap();
+        assert_eq!(vm.get_cells(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn start_with_head_outside() {
+        let p = get_small_program();
+        let res = VirtualMachine::with_tape(3, false, p, &[], 3);
+        assert_eq!(
+            res.err().unwrap().to_string(),
+            "Head position 3 is outside of the tape of 3 cells."
+        );
+    }
+
+    #[test]
+    fn input_at_eof() {
+        let p = get_small_program();
+        let mut vm = VirtualMachine::with_tape(3, false, p, &[42], 0).unwrap();
+        let mut empty = Cursor::new(Vec::new());
+
+        assert_eq!(vm.input(&mut empty).ok(), Some(1));
+        assert_eq!(vm.get_cells()[0], 42);
+
+        vm.set_eof_mode(EofMode::Max);
+        assert_eq!(vm.input(&mut empty).ok(), Some(2));
+        assert_eq!(vm.get_cells()[0], 255);
+
+        vm.set_eof_mode(EofMode::Zero);
+        assert_eq!(vm.input(&mut empty).ok(), Some(3));
+        assert_eq!(vm.get_cells()[0], 0);
+    }
+
+    #[test]
+    fn stop_at_step_limit() {
+        let p = Program::new("test.bf".to_string(), "+[]");
+        let mut vm = VirtualMachine::new(3, false, p);
+        vm.set_step_limit(Some(100));
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        let res = vm.interpret(&mut input, &mut output);
+        assert_eq!(res.err().unwrap().to_string(), "Step limit of 100 reached.");
+        assert_eq!(vm.get_steps(), 100);
+    }
+
+    #[test]
+    fn build_with_wide_cells() {
+        let p = Program::new("test.bf".to_string(), "-.,");
+        let mut vm = VirtualMachine::builder()
+            .tape_size(2)
+            .cell_width::<u16>()
+            .eof(EofMode::Max)
+            .step_limit(10)
+            .build(p)
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.get_cells(), &[65535u16, 0]);
+        assert_eq!(vm.get_steps(), 3);
+    }
+
+    #[test]
+    fn build_with_io_adapters() {
+        let p = Program::new("test.bf".to_string(), ",+.");
+        let mut vm = VirtualMachine::builder()
+            .tape(&[7, 8])
+            .head(1)
+            .input(Cursor::new(vec![64]))
+            .output(std::io::sink())
+            .build(p)
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(&vm.get_cells()[..2], &[7, 65]);
+    }
+
+    #[test]
+    fn run_code_from_string() {
+        assert_eq!(run_str(",[.[-],]", b"echo").unwrap(), b"echo");
+        assert_eq!(
+            run_str("[", b"").err().unwrap().to_string(),
+            "Extra open bracket at line 1 column 1."
+        );
+    }
+
+    #[test]
+    fn do_u16_wrapping() {
+        let mut num = 0_u16;
+        num.wrapping_decrement();
+        assert_eq!(num, 65535);
+        assert_eq!(num.to_byte(), 255);
+    }
+
+    #[test]
+    fn extended_type_one() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Ebf1);
+        // 6 into storage, then 6 << 1 = 12, 12 ^ 6 = 10, !10 = 245, 245 >> 1 = 122
+        let p =
+            Program::with_dialect("test.bf".to_string(), "++++++${^~}>!&>!|@+", &dialect).unwrap();
+        let mut vm = VirtualMachine::new(4, false, p);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        vm.interpret(&mut input, &mut output).unwrap();
+        assert_eq!(vm.get_cells(), &[122, 6, 6, 0]);
+        assert_eq!(vm.get_storage(), 6);
+    }
+
+    #[test]
+    fn undo_storage() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Ebf1);
+        let p = Program::with_dialect("test.bf".to_string(), "+$+$", &dialect).unwrap();
+        let mut vm = VirtualMachine::new(1, false, p);
+        vm.enable_history(10);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        vm.interpret(&mut input, &mut output).unwrap();
+        assert_eq!(vm.get_storage(), 2);
+        vm.step_back().unwrap();
+        assert_eq!(vm.get_storage(), 1);
+    }
+
+    #[test]
+    fn debug_dump() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Debug);
+        let p = Program::with_dialect("test.bf".to_string(), "+>++#", &dialect).unwrap();
+        let mut vm = VirtualMachine::new(3, false, p);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        for _ in 0..4 {
+            vm.step(&mut input, &mut output).unwrap();
+        }
+        assert_eq!(
+            vm.debug_state(),
+            "head: 1 ip: 4 at test.bf:1:5 cells 0..3: 1 [2] 0"
+        );
+    }
+
+    #[test]
+    fn embedded_input() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Separator);
+        let p = Program::with_dialect("test.bf".to_string(), ",.,.!hi", &dialect).unwrap();
+        let out = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut vm = VirtualMachine::builder()
+            .output(SharedWriter(out.clone()))
+            .build(p)
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(&out.lock().unwrap()[..], b"hi");
+    }
+
+    /// A writer the test can still read after giving it to the machine.
+    struct SharedWriter(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pbrain_procedures() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Pbrain);
+        // Procedure 0 adds 65, then call it twice from cell 1 and print
+        let code = "(++++++++[>++++++++<-]>+<)>::.";
+        let p = Program::with_dialect("test.bf".to_string(), code, &dialect).unwrap();
+        p.validate().unwrap();
+        let mut vm = VirtualMachine::new(3, false, p);
+        vm.enable_history(1000);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        // Cell 1 is 0, so procedure 0 runs there, writing to cell 2
+        vm.interpret(&mut input, &mut output).unwrap();
+        assert_eq!(vm.get_cells(), &[0, 0, 130]);
+        assert_eq!(vm.get_call_depth(), 0);
+
+        // Undo into the second call
+        while vm.get_call_depth() == 0 {
+            vm.step_back().unwrap();
+        }
+        assert_eq!(vm.get_call_depth(), 1);
+        while vm.step_back().unwrap() {}
+        assert_eq!(vm.get_ip(), 0);
+        assert_eq!(vm.get_call_depth(), 0);
+    }
+
+    #[test]
+    fn pbrain_errors() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Pbrain);
+        let p = Program::with_dialect("test.bf".to_string(), "+:", &dialect).unwrap();
+        let mut vm = VirtualMachine::new(3, false, p);
+        let error = vm.run().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Procedure 1 is not defined at line 1 column 2."
+        );
+
+        // A procedure calling itself forever
+        let p = Program::with_dialect("test.bf".to_string(), "(:):", &dialect).unwrap();
+        let mut vm = VirtualMachine::new(3, false, p);
+        let error = vm.run().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Call stack overflow at line 1 column 2, more than 10000 nested calls."
+        );
+    }
+
+    #[test]
+    fn grid_moves() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Grid);
+        let p = Program::with_dialect("test.bf".to_string(), "+v>+v+^^", &dialect).unwrap();
+        let mut vm = VirtualMachine::builder()
+            .tape_size(8)
+            .memory(MemoryModel::Grid(3))
+            .build(p)
+            .unwrap();
+        // Rounded up to full rows
+        assert_eq!(vm.get_cells().len(), 9);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+        vm.interpret(&mut input, &mut output).unwrap();
+        assert_eq!(vm.get_cells(), &[1, 0, 0, 0, 1, 0, 0, 1, 0]);
+        assert_eq!(vm.get_head(), 1);
+
+        let error = vm.move_head_up().err().unwrap();
+        assert_eq!(error.to_string(), "Already at the top of the grid.");
+        vm.move_head_right().unwrap();
+        let error = vm.move_head_right().err().unwrap();
+        assert_eq!(error.to_string(), "Already at the right edge of the grid.");
+    }
+
+    #[test]
+    fn grid_grows_down() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Grid);
+        let p = Program::with_dialect("test.bf".to_string(), "vvv+", &dialect).unwrap();
+        let mut vm = VirtualMachine::builder()
+            .tape_size(4)
+            .growable(true)
+            .memory(MemoryModel::Grid(2))
+            .build(p)
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.get_cells(), &[0, 0, 0, 0, 0, 0, 1, 0]);
+
+        // Up and down make no sense on a tape
+        let p = Program::with_dialect("test.bf".to_string(), "v", &dialect).unwrap();
+        let mut vm = VirtualMachine::new(4, false, p);
+        let error = vm.run().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Moving up and down needs the 2d memory model."
+        );
+    }
+
+    #[test]
+    fn bidirectional_tape() {
+        let p = Program::new("test.bf".to_string(), "+<<+++<+>>>>>+");
+        let mut vm = VirtualMachine::builder()
+            .tape_size(2)
+            .bidirectional(true)
+            .history(100)
+            .build(p)
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.get_position(), 2);
+        let origin = vm.get_origin();
+        assert_eq!(origin, 16);
+        let cells = vm.get_cells();
+        assert_eq!(&cells[origin - 3..origin + 3], &[1, 3, 0, 1, 0, 1]);
+
+        let mut out = Vec::new();
+        vm.dump_tape(&mut out, crate::DumpFormat::Decimal).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Tape (head at cell 2):\n     -16:   0   0   0   0   0   0   0   0   0   0   0   0   0   1   3   0\n       0:   1   0*  1\n"
+        );
+
+        // The undo log still points to the right cells
+        assert!(vm.reverse_continue(-2).unwrap());
+        assert_eq!(vm.get_ip(), 5);
+        assert_eq!(vm.get_cell(origin - 2), 2);
+        while vm.step_back().unwrap() {}
+        assert_eq!(vm.get_position(), 0);
+        assert!(vm.get_cells().iter().all(|cell| *cell == 0));
+    }
+
+    #[test]
+    fn fit_tape() {
+        let p = Program::new("test.bf".to_string(), "++++[>++<-]>>+<[>.<-]");
+        let mut vm = VirtualMachine::builder()
+            .fit_tape(true)
+            .output(Vec::new())
+            .build(p)
+            .unwrap();
+        assert_eq!(vm.get_cells().len(), 3);
+        vm.run().unwrap();
+
+        // The input decides how far the head goes
+        let p = Program::new("test.bf".to_string(), ",[>,]");
+        let vm = VirtualMachine::builder().fit_tape(true).build(p).unwrap();
+        assert_eq!(vm.get_cells().len(), 30000);
+        let p = Program::new("test.bf".to_string(), ">>");
+        let vm = VirtualMachine::builder()
+            .fit_tape(true)
+            .tape_size(10)
+            .build(p)
+            .unwrap();
+        assert_eq!(vm.get_cells().len(), 10);
+    }
+
+    #[test]
+    fn sparse_storage() {
+        let p = Program::new(
+            "test.bf".to_string(),
+            "+>>>>>>>>>>+<<<<<<<<<<[>>>>>>>>>>+<<<<<<<<<<-]",
+        );
+        let mut vm = VirtualMachine::builder()
+            .tape_size(5)
+            .growable(true)
+            .storage(Storage::Sparse)
+            .build(p)
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.get_tape().len(), 11);
+        assert_eq!(vm.get_cell(10), 2);
+        assert_eq!(vm.get_range(8..11), &[0, 0, 2]);
+    }
+
+    /// Records the writes and flushes it gets, and fails after `limit` bytes.
+    #[derive(Default)]
+    struct FlushCounter {
+        written: Vec<u8>,
+        flushes: Vec<usize>,
+        limit: Option<usize>,
+    }
+
+    impl std::io::Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if let Some(limit) = self.limit {
+                if self.written.len() + buf.len() > limit {
+                    return Err(std::io::ErrorKind::BrokenPipe.into());
+                }
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes.push(self.written.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_policies() {
+        // Prints "a\nb\n"
+        let code = "++++++++++[>++++++++++>+<<-]>---.>.<+.>.";
+        for (policy, flushes) in [
+            (FlushPolicy::Byte, vec![1, 2, 3, 4, 4]),
+            (FlushPolicy::Line, vec![2, 4, 4]),
+            (FlushPolicy::Exit, vec![4]),
+        ] {
+            let p = Program::new("test.bf".to_string(), code);
+            let mut vm = VirtualMachine::new(0, false, p);
+            vm.set_flush_policy(policy);
+            let mut output = FlushCounter::default();
+            vm.interpret(&mut std::io::empty(), &mut output).unwrap();
+            assert_eq!(output.written, b"a\nb\n");
+            assert_eq!(output.flushes, flushes, "{}", policy);
+        }
+    }
+
+    #[test]
+    fn broken_pipe() {
+        let p = Program::new("test.bf".to_string(), "+[.]");
+        let mut vm = VirtualMachine::new(0, false, p);
+        vm.set_flush_policy(FlushPolicy::Byte);
+        let mut output = FlushCounter {
+            limit: Some(3),
+            ..Default::default()
+        };
+        let error = vm.interpret(&mut std::io::empty(), &mut output).err();
+        assert_eq!(error.unwrap().kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(output.written, &[1, 1, 1]);
+    }
+
+    /// A reader which always fails.
+    struct FailingReader;
+
+    impl std::io::Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "reader is gone",
+            ))
+        }
+    }
+
+    /// A writer which fails on write, or only on flush.
+    struct FailingWriter {
+        fail_write: bool,
+    }
+
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.fail_write {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "no writing",
+                ));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "no flushing",
+            ))
+        }
+    }
+
+    #[test]
+    fn input_error() {
+        let p = Program::new("test.bf".to_string(), "+\n ,.");
+        let mut vm = VirtualMachine::new(0, false, p);
+        let mut output = Vec::new();
+        let error = vm.interpret(&mut FailingReader, &mut output).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            error.to_string(),
+            "Input failed at line 2 column 2 (ConnectionReset): reader is gone"
+        );
+        // Still at the `,`, and it was not counted as done
+        assert_eq!(vm.get_ip(), 2);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn output_write_error() {
+        let p = Program::new("test.bf".to_string(), "+.");
+        let mut vm = VirtualMachine::new(0, false, p);
+        let mut output = FailingWriter { fail_write: true };
+        let error = vm
+            .step(&mut std::io::empty(), &mut output)
+            .and_then(|_| vm.step(&mut std::io::empty(), &mut output))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            error.to_string(),
+            "Output failed at line 1 column 2 (PermissionDenied): no writing"
+        );
+        assert_eq!(vm.get_ip(), 1);
+    }
+
+    #[test]
+    fn error_in_included_file() {
+        let p = Program::new("main.bf".to_string(), "+");
+        let mut sources = p.source_map().clone();
+        let file_id = sources.add("lib.bf".to_string(), "\n.");
+        let span = Span::new(2, 1, 1).in_file(file_id);
+        let p = p.with_instructions(sources, vec![Instruction::new(Opcode::Output, span)]);
+        let mut vm = VirtualMachine::new(0, false, p);
+        let mut output = FailingWriter { fail_write: true };
+        let error = vm.step(&mut std::io::empty(), &mut output).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Output failed at line 2 column 1 of lib.bf (PermissionDenied): no writing"
+        );
+    }
+
+    #[test]
+    fn output_flush_error() {
+        // The byte policy flushes right after the write
+        let p = Program::new("test.bf".to_string(), "+.");
+        let mut vm = VirtualMachine::new(0, false, p);
+        vm.set_flush_policy(FlushPolicy::Byte);
+        let mut output = FailingWriter { fail_write: false };
+        let error = vm.interpret(&mut std::io::empty(), &mut output).err();
+        assert_eq!(
+            error.unwrap().to_string(),
+            "Output failed at line 1 column 2 (PermissionDenied): no flushing"
+        );
+
+        // The flush before reading the input fails
+        let p = Program::new("test.bf".to_string(), "+.,");
+        let mut vm = VirtualMachine::new(0, false, p);
+        let mut output = FailingWriter { fail_write: false };
+        let error = vm.interpret(&mut std::io::empty(), &mut output).err();
+        assert_eq!(
+            error.unwrap().to_string(),
+            "Output failed at line 1 column 3 (PermissionDenied): no flushing"
+        );
+
+        // With nothing to flush on the way, the final flush still reports it
+        let p = Program::new("test.bf".to_string(), "+.");
+        let mut vm = VirtualMachine::new(0, false, p);
+        let mut output = FailingWriter { fail_write: false };
+        let error = vm.interpret(&mut std::io::empty(), &mut output).err();
+        assert_eq!(error.unwrap().to_string(), "no flushing");
+    }
+
     #[test]
     fn do_u8_increment_big() {
-        let mut num = 255 as u8;
+        let mut num = 255_u8;
         num.wrapping_increment();
         assert_eq!(num, 0);
     }
     #[test]
     fn do_u8_increment_small() {
-        let mut num = 253 as u8;
+        let mut num = 253_u8;
         num.wrapping_increment();
         assert_eq!(num, 254);
     }
     #[test]
     fn do_u8_derement_big() {
-        let mut num = 0 as u8;
+        let mut num = 0_u8;
         num.wrapping_decrement();
         assert_eq!(num, 255);
     }
     #[test]
     fn do_u8_decrement_small() {
-        let mut num = 253 as u8;
+        let mut num = 253_u8;
         num.wrapping_decrement();
         assert_eq!(num, 252);
     }
@@ -0,0 +1,58 @@
+//! How the cells of the machine are laid out.
+
+use std::fmt;
+
+/// The shape of the memory the head moves on.
+///
+/// The cells are always stored in one flat vector. In a grid, row `y` and
+/// column `x` is the cell `y * width + x`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum MemoryModel {
+    /// The classic one dimensional tape.
+    #[default]
+    Tape,
+    /// A two dimensional grid with this many cells in every row. `^` and `v`
+    /// move the head one row up and down.
+    Grid(usize),
+}
+
+impl MemoryModel {
+    /// Number of cells in one row, the whole tape is one long row.
+    pub fn row_width(&self, cells: usize) -> usize {
+        match *self {
+            MemoryModel::Tape => cells,
+            MemoryModel::Grid(width) => width,
+        }
+    }
+
+    /// Returns the column and row of the cell.
+    pub fn position(&self, index: usize) -> (usize, usize) {
+        match *self {
+            MemoryModel::Tape => (index, 0),
+            MemoryModel::Grid(width) => (index % width, index / width),
+        }
+    }
+}
+
+impl fmt::Display for MemoryModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryModel::Tape => write!(f, "tape"),
+            MemoryModel::Grid(width) => write!(f, "2d grid of width {}", width),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::MemoryModel;
+
+    #[test]
+    fn grid_position() {
+        let grid = MemoryModel::Grid(10);
+        assert_eq!(grid.position(0), (0, 0));
+        assert_eq!(grid.position(23), (3, 2));
+        assert_eq!(grid.row_width(100), 10);
+        assert_eq!(MemoryModel::Tape.position(23), (23, 0));
+    }
+}
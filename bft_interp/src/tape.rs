@@ -0,0 +1,271 @@
+//! Storage backends for the cells of the machine.
+
+use crate::CellKind;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of cells in one page of a [`SparseTape`].
+const PAGE_SIZE: usize = 1024;
+
+/// Storage for the cells of a [`crate::VirtualMachine`].
+///
+/// Every cell from `0` to `len() - 1` exists and starts as `C::default()`,
+/// the backend decides how much memory that really takes.
+pub trait Tape<C: CellKind>: fmt::Debug {
+    /// Number of cells on the tape.
+    fn len(&self) -> usize;
+
+    /// Tells us if the tape has no cells at all.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value of the cell at `index`.
+    fn get(&self, index: usize) -> C;
+
+    /// Writes the value of the cell at `index`.
+    fn set(&mut self, index: usize, value: C);
+
+    /// Changes the number of cells, new cells at the end are zero.
+    fn resize(&mut self, len: usize);
+
+    /// Adds at least `cells` zero cells before the first cell, and returns
+    /// how many it really added. All the old cells move that much to the right.
+    fn grow_front(&mut self, cells: usize) -> usize;
+
+    /// Returns the indices of the first and the last non-zero cells, or `None`
+    /// if all the cells are zero.
+    fn used(&self) -> Option<(usize, usize)>;
+
+    /// Borrows all the cells, if the backend keeps them in one slice.
+    fn as_slice(&self) -> Option<&[C]> {
+        None
+    }
+}
+
+/// The dense backend, one vector element per cell.
+impl<C: CellKind> Tape<C> for Vec<C> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn get(&self, index: usize) -> C {
+        self[index]
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        self[index] = value;
+    }
+
+    fn resize(&mut self, len: usize) {
+        Vec::resize(self, len, C::default());
+    }
+
+    fn grow_front(&mut self, cells: usize) -> usize {
+        self.splice(0..0, std::iter::repeat_n(C::default(), cells));
+        cells
+    }
+
+    fn used(&self) -> Option<(usize, usize)> {
+        let zero = C::default();
+        let first = self.iter().position(|&c| c != zero)?;
+        let last = self.iter().rposition(|&c| c != zero)?;
+        Some((first, last))
+    }
+
+    fn as_slice(&self) -> Option<&[C]> {
+        Some(self)
+    }
+}
+
+/// A sparse backend which only keeps the pages of cells that were written.
+///
+/// Reading a cell never allocates, and writing a zero into a missing page does
+/// not create the page, so the memory use follows the cells the program really
+/// touched, no matter how long the tape is.
+#[derive(Debug, Default)]
+pub struct SparseTape<C> {
+    /// Number of cells on the tape.
+    len: usize,
+    /// The written pages, by their page number.
+    pages: BTreeMap<usize, Box<[C]>>,
+}
+
+impl<C: CellKind> SparseTape<C> {
+    /// Creates a tape of `len` zero cells, without allocating any of them.
+    pub fn new(len: usize) -> Self {
+        SparseTape {
+            len,
+            pages: BTreeMap::new(),
+        }
+    }
+
+    /// Number of pages holding cells in memory.
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+impl<C: CellKind> Tape<C> for SparseTape<C> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, index: usize) -> C {
+        assert!(index < self.len, "Cell {} is outside of the tape.", index);
+        match self.pages.get(&(index / PAGE_SIZE)) {
+            Some(page) => page[index % PAGE_SIZE],
+            None => C::default(),
+        }
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        assert!(index < self.len, "Cell {} is outside of the tape.", index);
+        let page = index / PAGE_SIZE;
+        if value == C::default() && !self.pages.contains_key(&page) {
+            return;
+        }
+        let page = self
+            .pages
+            .entry(page)
+            .or_insert_with(|| vec![C::default(); PAGE_SIZE].into_boxed_slice());
+        page[index % PAGE_SIZE] = value;
+    }
+
+    fn resize(&mut self, len: usize) {
+        if len < self.len {
+            // Clear the cells after the new end, so they are zero if we grow again
+            for index in len..len.div_ceil(PAGE_SIZE) * PAGE_SIZE {
+                if let Some(page) = self.pages.get_mut(&(index / PAGE_SIZE)) {
+                    page[index % PAGE_SIZE] = C::default();
+                }
+            }
+            let first_unused = len.div_ceil(PAGE_SIZE);
+            self.pages.split_off(&first_unused);
+        }
+        self.len = len;
+    }
+
+    fn grow_front(&mut self, cells: usize) -> usize {
+        // Whole pages only, so the cells keep their place inside the pages
+        let pages = cells.div_ceil(PAGE_SIZE).max(1);
+        let old = std::mem::take(&mut self.pages);
+        self.pages = old
+            .into_iter()
+            .map(|(page, values)| (page + pages, values))
+            .collect();
+        self.len += pages * PAGE_SIZE;
+        pages * PAGE_SIZE
+    }
+
+    fn used(&self) -> Option<(usize, usize)> {
+        let zero = C::default();
+        let first = self.pages.iter().find_map(|(page, values)| {
+            let offset = values.iter().position(|&c| c != zero)?;
+            Some(page * PAGE_SIZE + offset)
+        })?;
+        let last = self.pages.iter().rev().find_map(|(page, values)| {
+            let offset = values.iter().rposition(|&c| c != zero)?;
+            Some(page * PAGE_SIZE + offset)
+        })?;
+        Some((first, last))
+    }
+}
+
+/// The available storage backends for the cells.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Storage {
+    /// One vector element per cell, the fastest for small tapes.
+    #[default]
+    Dense,
+    /// Only the touched pages of cells are kept in memory, see [`SparseTape`].
+    Sparse,
+}
+
+impl Storage {
+    /// Creates an empty tape of `len` cells with this backend.
+    pub fn create<C: CellKind>(&self, len: usize) -> Box<dyn Tape<C> + Send> {
+        match self {
+            Storage::Dense => Box::new(vec![C::default(); len]),
+            Storage::Sparse => Box::new(SparseTape::new(len)),
+        }
+    }
+}
+
+impl FromStr for Storage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dense" => Ok(Storage::Dense),
+            "sparse" => Ok(Storage::Sparse),
+            _ => Err(format!("Unknown storage {}, use dense or sparse.", s)),
+        }
+    }
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Storage::Dense => write!(f, "dense"),
+            Storage::Sparse => write!(f, "sparse"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tape::{SparseTape, Tape, PAGE_SIZE};
+
+    #[test]
+    fn sparse_only_keeps_written_pages() {
+        let mut tape: SparseTape<u8> = SparseTape::new(usize::MAX / 2);
+        assert_eq!(tape.get(1_000_000_000), 0);
+        tape.set(5, 0);
+        assert_eq!(tape.pages(), 0);
+        tape.set(1_000_000_000, 7);
+        tape.set(3, 1);
+        assert_eq!(tape.pages(), 2);
+        assert_eq!(tape.get(1_000_000_000), 7);
+        assert_eq!(tape.used(), Some((3, 1_000_000_000)));
+    }
+
+    #[test]
+    fn sparse_grows_in_front() {
+        let mut tape: SparseTape<u8> = SparseTape::new(10);
+        tape.set(2, 9);
+        assert_eq!(tape.grow_front(1), PAGE_SIZE);
+        assert_eq!(tape.len(), PAGE_SIZE + 10);
+        assert_eq!(tape.get(PAGE_SIZE + 2), 9);
+        assert_eq!(tape.get(2), 0);
+    }
+
+    #[test]
+    fn sparse_shrinks() {
+        let mut tape: SparseTape<u8> = SparseTape::new(3000);
+        tape.set(2500, 1);
+        tape.set(2049, 1);
+        tape.resize(2049);
+        tape.resize(3000);
+        assert_eq!(tape.get(2049), 0);
+        assert_eq!(tape.get(2500), 0);
+        assert_eq!(tape.used(), None);
+    }
+
+    #[test]
+    fn dense_and_sparse_agree() {
+        let mut dense: Vec<u16> = vec![0; 2000];
+        let mut sparse: SparseTape<u16> = SparseTape::new(2000);
+        for (index, value) in [(0, 1), (1500, 300), (1999, 2)] {
+            dense.set(index, value);
+            sparse.set(index, value);
+        }
+        for index in 0..2000 {
+            assert_eq!(Tape::get(&dense, index), sparse.get(index));
+        }
+        assert_eq!(dense.used(), sparse.used());
+        assert_eq!(Tape::as_slice(&dense).map(|cells| cells[1500]), Some(300));
+        assert_eq!(sparse.as_slice(), None);
+    }
+}
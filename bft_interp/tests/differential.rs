@@ -0,0 +1,358 @@
+//! Differential fuzzing of the interpreter.
+//!
+//! proptest generates random bracket-balanced programs and inputs. They run
+//! on the [`VirtualMachine`] with various tape sizes and cell widths, and on a
+//! small reference interpreter written here from the definition of the
+//! language. They also run on every other way the workspace has to execute a
+//! program: stepping, `run` with the builder I/O, the sparse storage, the
+//! bidirectional tape, the undo log, the folded prefix, the async interpreter
+//! with the `async` feature, and the static analysis. There is no JIT,
+//! optimised IR or compiled backend to compare with yet.
+//!
+//! proptest shrinks a failing case to a minimal program and input, and saves
+//! it in `differential.proptest-regressions` so it runs first from then on.
+
+use bft_interp::{fold_prefix, CellKind, Storage, VirtualMachine};
+use bft_types::Program;
+use proptest::prelude::*;
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+
+/// Steps before a program is stopped.
+const STEP_LIMIT: u64 = 2000;
+
+/// One instruction, or a loop around more.
+#[derive(Debug, Clone)]
+enum Node {
+    Op(char),
+    Loop(Vec<Node>),
+}
+
+/// Random programs, as a tree so that shrinking keeps the brackets balanced.
+fn program() -> impl Strategy<Value = String> {
+    let op = prop::sample::select(vec!['+', '+', '-', '>', '>', '<', '.', ',']).prop_map(Node::Op);
+    let node = op.prop_recursive(4, 64, 8, |inner| {
+        prop::collection::vec(inner, 0..8).prop_map(Node::Loop)
+    });
+    prop::collection::vec(node, 0..24).prop_map(|nodes| {
+        let mut code = String::new();
+        render(&nodes, &mut code);
+        code
+    })
+}
+
+fn render(nodes: &[Node], code: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Op(op) => code.push(*op),
+            Node::Loop(body) => {
+                code.push('[');
+                render(body, code);
+                code.push(']');
+            }
+        }
+    }
+}
+
+fn input() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(prop::sample::select(vec![0u8, 1, 2, 255]), 0..6)
+}
+
+/// How a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum End {
+    Done,
+    /// `<` on the first cell.
+    Left,
+    /// `>` on the last cell of a tape which can not grow.
+    Right,
+    Limit,
+}
+
+impl End {
+    fn of(result: &Result<(), std::io::Error>) -> End {
+        match result {
+            Ok(()) => End::Done,
+            Err(e) if e.to_string().contains("beginning of the tape") => End::Left,
+            Err(e) if e.to_string().contains("end of the tape") => End::Right,
+            Err(e) if e.to_string().contains("Step limit") => End::Limit,
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+}
+
+/// What a run did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Outcome {
+    output: Vec<u8>,
+    end: End,
+    tape: Vec<u64>,
+    head: usize,
+}
+
+/// What the reference run saw on the way.
+struct Trace {
+    outcome: Outcome,
+    /// Highest cell the head was on.
+    max_head: usize,
+    /// Index of every `[` which ran.
+    loops: Vec<usize>,
+}
+
+/// Runs the program the simplest way, on cells holding values below
+/// `modulus`, with the end of input leaving the cell unchanged.
+fn reference(code: &str, input: &[u8], size: usize, growable: bool, modulus: u64) -> Trace {
+    let code = code.as_bytes();
+    let mut jumps = vec![0; code.len()];
+    let mut stack = Vec::new();
+    for (pos, op) in code.iter().enumerate() {
+        match op {
+            b'[' => stack.push(pos),
+            b']' => {
+                let open = stack.pop().unwrap();
+                jumps[open] = pos;
+                jumps[pos] = open;
+            }
+            _ => (),
+        }
+    }
+
+    let mut tape = vec![0u64; size];
+    let (mut head, mut ip, mut steps, mut max_head) = (0, 0, 0, 0);
+    let mut input = input.iter();
+    let mut output = Vec::new();
+    let mut loops = Vec::new();
+    let end = loop {
+        if ip == code.len() {
+            break End::Done;
+        }
+        if steps >= STEP_LIMIT {
+            break End::Limit;
+        }
+        steps += 1;
+        match code[ip] {
+            b'+' => tape[head] = (tape[head] + 1) % modulus,
+            b'-' => tape[head] = (tape[head] + modulus - 1) % modulus,
+            b'<' if head == 0 => break End::Left,
+            b'<' => head -= 1,
+            b'>' if head == tape.len() - 1 && !growable => break End::Right,
+            b'>' => {
+                if head == tape.len() - 1 {
+                    tape.push(0);
+                }
+                head += 1;
+                max_head = max_head.max(head);
+            }
+            b'.' => output.push(tape[head] as u8),
+            b',' => {
+                if let Some(byte) = input.next() {
+                    tape[head] = *byte as u64;
+                }
+            }
+            b'[' => {
+                loops.push(ip);
+                if tape[head] == 0 {
+                    ip = jumps[ip];
+                }
+            }
+            b']' if tape[head] != 0 => ip = jumps[ip],
+            _ => (),
+        }
+        ip += 1;
+    };
+    Trace {
+        outcome: Outcome {
+            output,
+            end,
+            tape,
+            head,
+        },
+        max_head,
+        loops,
+    }
+}
+
+/// Runs the program on a machine with `C` cells.
+fn run_vm<C: CellKind + Into<u64>>(
+    code: &str,
+    input: &[u8],
+    size: usize,
+    growable: bool,
+) -> Outcome {
+    let program = Program::new("fuzz.bf".to_string(), code);
+    let mut vm = VirtualMachine::builder()
+        .tape_size(size)
+        .growable(growable)
+        .step_limit(STEP_LIMIT)
+        .cell_width::<C>()
+        .build(program)
+        .unwrap();
+    let mut output = Vec::new();
+    let result = vm.interpret(&mut &input[..], &mut output);
+    Outcome {
+        output,
+        end: End::of(&result),
+        tape: vm.get_cells().iter().map(|cell| (*cell).into()).collect(),
+        head: vm.get_head(),
+    }
+}
+
+/// Output and result of a run, to compare the ways of running a program.
+fn finish(
+    vm: &VirtualMachine,
+    output: Vec<u8>,
+    result: Result<(), std::io::Error>,
+) -> (Vec<u8>, String, Vec<u8>, usize) {
+    let error = result.err().map(|e| e.to_string()).unwrap_or_default();
+    let tape = vm.get_range(0..vm.get_tape().len());
+    (output, error, tape, vm.get_head())
+}
+
+/// A writer the test keeps a handle on, for [`VirtualMachine::run`].
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn machine_matches_reference(
+        code in program(),
+        input in input(),
+        size in 1usize..12,
+        growable in any::<bool>(),
+    ) {
+        let expected = reference(&code, &input, size, growable, 1 << 8).outcome;
+        prop_assert_eq!(run_vm::<u8>(&code, &input, size, growable), expected);
+        let expected = reference(&code, &input, size, growable, 1 << 16).outcome;
+        prop_assert_eq!(run_vm::<u16>(&code, &input, size, growable), expected);
+        let expected = reference(&code, &input, size, growable, 1 << 32).outcome;
+        prop_assert_eq!(run_vm::<u32>(&code, &input, size, growable), expected);
+    }
+
+    #[test]
+    fn execution_paths_agree(code in program(), input in input(), size in 1usize..12) {
+        let program = Program::new("fuzz.bf".to_string(), &code);
+        let machine = || {
+            VirtualMachine::builder()
+                .tape_size(size)
+                .step_limit(STEP_LIMIT)
+        };
+
+        let mut vm = machine().build(program.clone()).unwrap();
+        let mut output = Vec::new();
+        let result = vm.interpret(&mut &input[..], &mut output);
+        let end = End::of(&result);
+        let expected = finish(&vm, output, result);
+
+        // One step at a time
+        let mut vm = machine().build(program.clone()).unwrap();
+        let mut output = Vec::new();
+        let mut reader = &input[..];
+        let result = loop {
+            match vm.step(&mut reader, &mut output) {
+                Ok(true) => (),
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        prop_assert_eq!(&finish(&vm, output, result), &expected, "step");
+
+        // With the I/O of the builder
+        let shared = Shared::default();
+        let mut vm = machine()
+            .input(Cursor::new(input.clone()))
+            .output(shared.clone())
+            .build(program.clone())
+            .unwrap();
+        let result = vm.run();
+        let output = shared.0.lock().unwrap().clone();
+        prop_assert_eq!(&finish(&vm, output, result), &expected, "run");
+
+        // Sparse storage
+        let mut vm = machine().storage(Storage::Sparse).build(program.clone()).unwrap();
+        let mut output = Vec::new();
+        let result = vm.interpret(&mut &input[..], &mut output);
+        prop_assert_eq!(&finish(&vm, output, result), &expected, "sparse");
+
+        // The undo log goes back to the start
+        let mut vm = machine().history(4096).build(program.clone()).unwrap();
+        let mut output = Vec::new();
+        let result = vm.interpret(&mut &input[..], &mut output);
+        prop_assert_eq!(&finish(&vm, output, result), &expected, "history");
+        while vm.step_back().unwrap() {}
+        prop_assert_eq!(vm.get_head(), 0);
+        prop_assert!(vm.get_cells().iter().all(|cell| *cell == 0));
+
+        // A bidirectional tape only differs when the head leaves the tape
+        if end == End::Done || end == End::Limit {
+            let mut vm = machine().bidirectional(true).build(program.clone()).unwrap();
+            let mut output = Vec::new();
+            let result = vm.interpret(&mut &input[..], &mut output);
+            prop_assert_eq!(&output, &expected.0, "bidirectional");
+            prop_assert_eq!(End::of(&result), end, "bidirectional");
+        }
+
+        // The prefix is folded on the default tape, so compare on it. The
+        // folded program takes no more steps, so it ends whenever the program
+        // ends before the limit
+        let folded = fold_prefix(&program, STEP_LIMIT).unwrap();
+        let mut results = Vec::new();
+        for program in [program.clone(), folded] {
+            let mut vm = VirtualMachine::builder().step_limit(STEP_LIMIT).build(program).unwrap();
+            let mut output = Vec::new();
+            let result = vm.interpret(&mut &input[..], &mut output);
+            results.push((output, End::of(&result), vm.get_steps()));
+        }
+        if results[0].1 != End::Limit {
+            prop_assert_eq!(&results[1].0, &results[0].0, "fold");
+            prop_assert_eq!(results[1].1, results[0].1, "fold");
+            prop_assert!(results[1].2 <= results[0].2, "fold is slower");
+        }
+
+        #[cfg(feature = "async")]
+        {
+            let mut vm = machine().build(program).unwrap();
+            let mut output = Vec::new();
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let result = runtime.block_on(vm.interpret_async(&mut &input[..], &mut output));
+            prop_assert_eq!(&finish(&vm, output, result), &expected, "async");
+        }
+    }
+
+    #[test]
+    fn analysis_is_sound(code in program(), input in input()) {
+        let program = Program::new("fuzz.bf".to_string(), &code);
+        let analysis = program.analyze().unwrap();
+        let trace = reference(&code, &input, 1, true, 1 << 8);
+
+        if let Some(max_cell) = analysis.max_cell {
+            prop_assert!(trace.max_head <= max_cell, "max cell {}", trace.max_head);
+        }
+        if trace.outcome.end == End::Left {
+            prop_assert!(!analysis.underflows.is_empty(), "underflow");
+        }
+        // The loops in the order of their `[`
+        let opens: Vec<usize> = code.match_indices('[').map(|(pos, _)| pos).collect();
+        for open in trace.loops.iter() {
+            let report = analysis.loops[opens.binary_search(open).unwrap()];
+            prop_assert!(report.reached, "loop at {} not reached", open);
+            if trace.outcome.end == End::Done {
+                prop_assert!(!report.never_ends, "loop at {} ended", open);
+            }
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Turns the syntax tree into Brainfuck instructions.
+//!
+//! Every variable and every temporary value gets a cell of its own. The
+//! compiler always knows which cell the head is on, so it moves the head with
+//! the right number of `<` and `>`, and every loop ends on the cell it started
+//! on. Free cells are always zero, so a new temporary needs no clearing.
+//! The variables get their cells before any code, so a temporary never uses
+//! the cell of a variable defined later in the same loop.
+
+use crate::lexer::error;
+use crate::parser::{BinOp, Expr, ExprKind, Item, Stmt, StmtKind};
+use bft_types::{Instruction, Opcode, Span};
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// The state of the code generator.
+#[derive(Debug, Default)]
+pub(crate) struct Codegen {
+    /// The instructions so far.
+    pub(crate) ins: Vec<Instruction>,
+    /// The cell under the head.
+    head: usize,
+    /// Which cells hold a variable or a temporary.
+    used: Vec<bool>,
+    /// The cells of the variables.
+    vars: HashMap<String, usize>,
+    /// The variables whose `let` was compiled already.
+    defined: HashSet<String>,
+    /// The span the next instructions point at.
+    span: Span,
+}
+
+impl Codegen {
+    /// Gives a cell to every variable defined in the statements.
+    pub(crate) fn declare(&mut self, stmts: &[Stmt]) -> io::Result<()> {
+        for stmt in stmts {
+            match &stmt.kind {
+                StmtKind::Let(name, _) => {
+                    if self.vars.contains_key(name) {
+                        return Err(error(
+                            &format!("Variable {} is already defined", name),
+                            stmt.span,
+                        ));
+                    }
+                    let cell = self.alloc();
+                    self.vars.insert(name.clone(), cell);
+                }
+                StmtKind::While(_, body) => self.declare(body)?,
+                StmtKind::If(_, then, otherwise) => {
+                    self.declare(then)?;
+                    self.declare(otherwise)?;
+                }
+                StmtKind::Assign(_, _) | StmtKind::Print(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the statements.
+    pub(crate) fn block(&mut self, stmts: &[Stmt]) -> io::Result<()> {
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> io::Result<()> {
+        self.span = stmt.span;
+        match &stmt.kind {
+            StmtKind::Let(name, value) | StmtKind::Assign(name, value) => {
+                let cell = self.expr(value)?;
+                if let StmtKind::Let(_, _) = stmt.kind {
+                    self.defined.insert(name.clone());
+                }
+                let var = self.var(name, stmt.span)?;
+                self.span = stmt.span;
+                // Inside a loop a new variable still holds its last value
+                self.clear(var);
+                self.move_to(cell, &[(var, true)]);
+                self.release(cell);
+            }
+            StmtKind::While(cond, body) => {
+                let cell = self.expr(cond)?;
+                self.goto(cell);
+                self.emit('[');
+                self.block(body)?;
+                self.span = stmt.span;
+                self.clear(cell);
+                let again = self.expr(cond)?;
+                self.move_to(again, &[(cell, true)]);
+                self.release(again);
+                self.goto(cell);
+                self.emit(']');
+                self.release(cell);
+            }
+            StmtKind::If(cond, then, otherwise) => {
+                let cell = self.expr(cond)?;
+                self.span = stmt.span;
+                self.if_else(cell, |gen| gen.block(then), |gen| gen.block(otherwise))?;
+            }
+            StmtKind::Print(items) => {
+                for item in items {
+                    match item {
+                        Item::Text(text) => {
+                            self.span = stmt.span;
+                            self.print_text(text);
+                        }
+                        Item::Value(value) => {
+                            let cell = self.expr(value)?;
+                            self.span = value.span;
+                            self.print_number(cell);
+                            self.free(cell);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes the expression into a new temporary cell.
+    fn expr(&mut self, expr: &Expr) -> io::Result<usize> {
+        self.span = expr.span;
+        let cell = match &expr.kind {
+            ExprKind::Number(value) => {
+                let cell = self.alloc();
+                self.add(cell, *value);
+                cell
+            }
+            ExprKind::Var(name) => {
+                let var = self.var(name, expr.span)?;
+                self.copy(var)
+            }
+            ExprKind::Input => {
+                let cell = self.alloc();
+                self.goto(cell);
+                self.emit(',');
+                cell
+            }
+            ExprKind::Not(inner) => {
+                let value = self.expr(inner)?;
+                self.span = expr.span;
+                let cell = self.not(value);
+                self.free(value);
+                cell
+            }
+            ExprKind::Binary(op, left, right) => {
+                let a = self.expr(left)?;
+                let b = self.expr(right)?;
+                self.span = expr.span;
+                match op {
+                    BinOp::Add | BinOp::Sub => {
+                        self.move_to(b, &[(a, *op == BinOp::Add)]);
+                        self.release(b);
+                        return Ok(a);
+                    }
+                    _ => (),
+                }
+                let cell = match op {
+                    BinOp::Mul => self.mul(a, b),
+                    BinOp::Div | BinOp::Mod => {
+                        let (quotient, remainder) = self.divmod(a, b);
+                        if *op == BinOp::Div {
+                            self.free(remainder);
+                            quotient
+                        } else {
+                            self.free(quotient);
+                            remainder
+                        }
+                    }
+                    BinOp::Eq => self.eq(a, b),
+                    BinOp::Ne => {
+                        let eq = self.eq(a, b);
+                        let cell = self.not(eq);
+                        self.free(eq);
+                        cell
+                    }
+                    BinOp::Lt => self.lt(a, b),
+                    BinOp::Gt => self.lt(b, a),
+                    BinOp::Le | BinOp::Ge => {
+                        let lt = if *op == BinOp::Le {
+                            self.lt(b, a)
+                        } else {
+                            self.lt(a, b)
+                        };
+                        let cell = self.not(lt);
+                        self.free(lt);
+                        cell
+                    }
+                    BinOp::Add | BinOp::Sub => unreachable!(),
+                };
+                self.free(a);
+                self.free(b);
+                cell
+            }
+        };
+        Ok(cell)
+    }
+
+    /// Returns the cell of the variable.
+    fn var(&self, name: &str, span: Span) -> io::Result<usize> {
+        match self.vars.get(name) {
+            Some(cell) if self.defined.contains(name) => Ok(*cell),
+            _ => Err(error(&format!("Unknown variable {}", name), span)),
+        }
+    }
+
+    fn emit(&mut self, ch: char) {
+        self.ins.push(Instruction::new(Opcode::from(ch), self.span));
+    }
+
+    /// Moves the head to the cell.
+    fn goto(&mut self, cell: usize) {
+        while self.head < cell {
+            self.emit('>');
+            self.head += 1;
+        }
+        while self.head > cell {
+            self.emit('<');
+            self.head -= 1;
+        }
+    }
+
+    /// Returns the lowest free cell, which is zero.
+    fn alloc(&mut self) -> usize {
+        match self.used.iter().position(|used| !used) {
+            Some(cell) => {
+                self.used[cell] = true;
+                cell
+            }
+            None => {
+                self.used.push(true);
+                self.used.len() - 1
+            }
+        }
+    }
+
+    /// Clears the cell and gives it back.
+    fn free(&mut self, cell: usize) {
+        self.clear(cell);
+        self.release(cell);
+    }
+
+    /// Gives back a cell which is already zero.
+    fn release(&mut self, cell: usize) {
+        self.used[cell] = false;
+    }
+
+    fn clear(&mut self, cell: usize) {
+        self.goto(cell);
+        self.emit('[');
+        self.emit('-');
+        self.emit(']');
+    }
+
+    /// Adds the value to the cell, going down when that is shorter.
+    fn add(&mut self, cell: usize, value: u8) {
+        self.goto(cell);
+        if value <= 128 {
+            (0..value).for_each(|_| self.emit('+'));
+        } else {
+            (0..256 - value as usize).for_each(|_| self.emit('-'));
+        }
+    }
+
+    /// Moves the value of `src` into the targets, adding it or subtracting it.
+    /// The `src` ends up zero.
+    fn move_to(&mut self, src: usize, targets: &[(usize, bool)]) {
+        self.goto(src);
+        self.emit('[');
+        self.emit('-');
+        for (target, add) in targets {
+            self.goto(*target);
+            self.emit(if *add { '+' } else { '-' });
+        }
+        self.goto(src);
+        self.emit(']');
+    }
+
+    /// Copies the cell into a new temporary.
+    fn copy(&mut self, src: usize) -> usize {
+        let cell = self.alloc();
+        let tmp = self.alloc();
+        self.move_to(src, &[(cell, true), (tmp, true)]);
+        self.move_to(tmp, &[(src, true)]);
+        self.release(tmp);
+        cell
+    }
+
+    /// Runs `then` if the cell is not zero and `otherwise` if it is. The cell
+    /// is used up.
+    fn if_else<T, E>(&mut self, cond: usize, then: T, otherwise: E) -> io::Result<()>
+    where
+        T: FnOnce(&mut Self) -> io::Result<()>,
+        E: FnOnce(&mut Self) -> io::Result<()>,
+    {
+        let span = self.span;
+        let flag = self.alloc();
+        self.add(flag, 1);
+        self.goto(cond);
+        self.emit('[');
+        then(self)?;
+        self.span = span;
+        self.clear(flag);
+        self.clear(cond);
+        self.emit(']');
+        self.release(cond);
+        self.goto(flag);
+        self.emit('[');
+        otherwise(self)?;
+        self.span = span;
+        self.clear(flag);
+        self.emit(']');
+        self.release(flag);
+        Ok(())
+    }
+
+    /// Returns 1 if the cell is zero, and 0 otherwise.
+    fn not(&mut self, cell: usize) -> usize {
+        let result = self.alloc();
+        self.add(result, 1);
+        let value = self.copy(cell);
+        self.goto(value);
+        self.emit('[');
+        self.clear(result);
+        self.clear(value);
+        self.emit(']');
+        self.release(value);
+        result
+    }
+
+    fn eq(&mut self, a: usize, b: usize) -> usize {
+        let diff = self.copy(a);
+        let other = self.copy(b);
+        self.move_to(other, &[(diff, false)]);
+        self.release(other);
+        let result = self.not(diff);
+        self.free(diff);
+        result
+    }
+
+    /// Returns 1 if `a < b`, by counting both down until one of them is zero.
+    fn lt(&mut self, a: usize, b: usize) -> usize {
+        let x = self.copy(a);
+        let y = self.copy(b);
+        let result = self.alloc();
+        self.goto(y);
+        self.emit('[');
+        let x_left = self.copy(x);
+        self.if_else(
+            x_left,
+            |gen| {
+                gen.add(x, 255);
+                gen.add(y, 255);
+                Ok(())
+            },
+            |gen| {
+                gen.add(result, 1);
+                gen.clear(y);
+                Ok(())
+            },
+        )
+        .expect("the branches can not fail");
+        self.goto(y);
+        self.emit(']');
+        self.release(y);
+        self.free(x);
+        result
+    }
+
+    fn mul(&mut self, a: usize, b: usize) -> usize {
+        let result = self.alloc();
+        let count = self.copy(a);
+        self.goto(count);
+        self.emit('[');
+        self.emit('-');
+        let value = self.copy(b);
+        self.move_to(value, &[(result, true)]);
+        self.release(value);
+        self.goto(count);
+        self.emit(']');
+        self.release(count);
+        result
+    }
+
+    /// Returns the quotient and the remainder of `a / b`, by subtracting `b`
+    /// while it fits. Dividing by zero gives 0 and `a`.
+    fn divmod(&mut self, a: usize, b: usize) -> (usize, usize) {
+        let quotient = self.alloc();
+        let remainder = self.copy(a);
+        let fits = self.divides(remainder, b);
+        self.goto(fits);
+        self.emit('[');
+        let value = self.copy(b);
+        self.move_to(value, &[(remainder, false)]);
+        self.release(value);
+        self.add(quotient, 1);
+        self.clear(fits);
+        let again = self.divides(remainder, b);
+        self.move_to(again, &[(fits, true)]);
+        self.release(again);
+        self.goto(fits);
+        self.emit(']');
+        self.release(fits);
+        (quotient, remainder)
+    }
+
+    /// Returns 1 if `b` is not zero and fits in `remainder`.
+    fn divides(&mut self, remainder: usize, b: usize) -> usize {
+        let lt = self.lt(remainder, b);
+        let fits = self.not(lt);
+        self.free(lt);
+        let zero = self.not(b);
+        self.goto(zero);
+        self.emit('[');
+        self.clear(fits);
+        self.clear(zero);
+        self.emit(']');
+        self.release(zero);
+        fits
+    }
+
+    /// Prints the bytes, stepping one cell from each byte to the next.
+    fn print_text(&mut self, text: &[u8]) {
+        let cell = self.alloc();
+        let mut current = 0u8;
+        for byte in text {
+            self.add(cell, byte.wrapping_sub(current));
+            self.emit('.');
+            current = *byte;
+        }
+        self.free(cell);
+    }
+
+    /// Prints the value of the cell as a decimal number, without leading
+    /// zeros.
+    fn print_number(&mut self, cell: usize) {
+        let hundred = self.alloc();
+        self.add(hundred, 100);
+        let (hundreds, rest) = self.divmod(cell, hundred);
+        self.free(hundred);
+        let ten = self.alloc();
+        self.add(ten, 10);
+        let (tens, ones) = self.divmod(rest, ten);
+        self.free(ten);
+        self.free(rest);
+
+        // The tens are printed if there are hundreds or tens
+        let any = self.copy(hundreds);
+        let tens_copy = self.copy(tens);
+        self.move_to(tens_copy, &[(any, true)]);
+        self.release(tens_copy);
+
+        let some = self.copy(hundreds);
+        self.goto(some);
+        self.emit('[');
+        self.add(hundreds, b'0');
+        self.emit('.');
+        self.clear(some);
+        self.emit(']');
+        self.release(some);
+
+        self.goto(any);
+        self.emit('[');
+        self.add(tens, b'0');
+        self.emit('.');
+        self.clear(any);
+        self.emit(']');
+        self.release(any);
+
+        self.add(ones, b'0');
+        self.emit('.');
+        for digit in [hundreds, tens, ones] {
+            self.free(digit);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Splits the source of the language into tokens.
+
+use bft_types::Span;
+use std::io;
+
+/// The symbols of the language, longest first so `==` wins over `=`.
+const SYMBOLS: &[&str] = &[
+    "==", "!=", "<=", ">=", "=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", "{", "}", ";",
+    ",",
+];
+
+/// One token of the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Token {
+    /// A variable name.
+    Ident(String),
+    /// A number, or a character literal like `'A'`.
+    Number(u32),
+    /// A string literal, as UTF-8 bytes.
+    Str(Vec<u8>),
+    Let,
+    While,
+    If,
+    Else,
+    Print,
+    Input,
+    /// One of the [`SYMBOLS`].
+    Symbol(&'static str),
+}
+
+/// Splits the source into tokens, each with the span of its first character.
+///
+/// `//` starts a comment up to the end of the line.
+pub(crate) fn tokenize(source: &str) -> io::Result<Vec<(Token, Span)>> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut tokens = Vec::new();
+    let (mut line, mut line_start) = (1, 0);
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let (offset, ch) = chars[pos];
+        let span = Span::new(line, pos - line_start + 1, offset);
+        if ch == '\n' {
+            line += 1;
+            line_start = pos + 1;
+            pos += 1;
+        } else if ch.is_whitespace() {
+            pos += 1;
+        } else if source[offset..].starts_with("//") {
+            while pos < chars.len() && chars[pos].1 != '\n' {
+                pos += 1;
+            }
+        } else if ch.is_ascii_digit() {
+            let end = scan(&chars, pos, |ch| ch.is_ascii_alphanumeric());
+            let text: String = chars[pos..end].iter().map(|(_, ch)| ch).collect();
+            let value = text
+                .parse()
+                .map_err(|_| error(&format!("Bad number {}", text), span))?;
+            tokens.push((Token::Number(value), span));
+            pos = end;
+        } else if ch.is_alphabetic() || ch == '_' {
+            let end = scan(&chars, pos, |ch| ch.is_alphanumeric() || ch == '_');
+            let word: String = chars[pos..end].iter().map(|(_, ch)| ch).collect();
+            let token = match word.as_str() {
+                "let" => Token::Let,
+                "while" => Token::While,
+                "if" => Token::If,
+                "else" => Token::Else,
+                "print" => Token::Print,
+                "input" => Token::Input,
+                _ => Token::Ident(word),
+            };
+            tokens.push((token, span));
+            pos = end;
+        } else if ch == '"' || ch == '\'' {
+            let mut text = String::new();
+            pos += 1;
+            loop {
+                match chars.get(pos).map(|(_, ch)| *ch) {
+                    Some(end) if end == ch => break,
+                    Some('\\') => {
+                        let escaped = match chars.get(pos + 1).map(|(_, ch)| *ch) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('0') => '\0',
+                            Some(ch @ ('\\' | '"' | '\'')) => ch,
+                            _ => return Err(error("Unknown escape in the string", span)),
+                        };
+                        text.push(escaped);
+                        pos += 2;
+                    }
+                    Some('\n') | None => return Err(error("Unterminated string", span)),
+                    Some(ch) => {
+                        text.push(ch);
+                        pos += 1;
+                    }
+                }
+            }
+            pos += 1;
+            if ch == '"' {
+                tokens.push((Token::Str(text.into_bytes()), span));
+            } else {
+                let mut chars = text.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => tokens.push((Token::Number(ch as u32), span)),
+                    _ => return Err(error("A character literal needs one character", span)),
+                }
+            }
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| source[offset..].starts_with(**symbol))
+                .ok_or_else(|| error(&format!("Unexpected character {}", ch), span))?;
+            tokens.push((Token::Symbol(symbol), span));
+            pos += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Returns the end of the run of characters matching `accept`.
+fn scan(chars: &[(usize, char)], pos: usize, accept: impl Fn(char) -> bool) -> usize {
+    chars[pos..]
+        .iter()
+        .position(|(_, ch)| !accept(*ch))
+        .map_or(chars.len(), |len| pos + len)
+}
+
+/// Creates a compile error at the span.
+pub(crate) fn error(msg: &str, span: Span) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} at line {} column {}.", msg, span.line, span.col),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::{tokenize, Token};
+
+    #[test]
+    fn tokens() {
+        let tokens = tokenize("let x = 'A';\n// comment\nprint \"hi\\n\", x>=10;").unwrap();
+        let kinds: Vec<Token> = tokens.iter().map(|(token, _)| token.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Token::Let,
+                Token::Ident("x".to_string()),
+                Token::Symbol("="),
+                Token::Number(65),
+                Token::Symbol(";"),
+                Token::Print,
+                Token::Str(b"hi\n".to_vec()),
+                Token::Symbol(","),
+                Token::Ident("x".to_string()),
+                Token::Symbol(">="),
+                Token::Number(10),
+                Token::Symbol(";"),
+            ]
+        );
+        let span = tokens[9].1;
+        assert_eq!((span.line, span.col, span.byte_offset), (3, 16, 39));
+    }
+
+    #[test]
+    fn bad_tokens() {
+        let error = |source| tokenize(source).err().unwrap().to_string();
+        assert_eq!(
+            error("let x = 1;\n  x = $"),
+            "Unexpected character $ at line 2 column 7."
+        );
+        assert_eq!(
+            error("print \"hi"),
+            "Unterminated string at line 1 column 7."
+        );
+        assert_eq!(error("x = 12ab;"), "Bad number 12ab at line 1 column 5.");
+    }
+}
@@ -0,0 +1,132 @@
+//! A small structured language which compiles down to Brainfuck.
+//!
+//! ```text
+//! let n = 5;
+//! while n > 0 {
+//!     print n, "\n";
+//!     n = n - 1;
+//! }
+//! if input == 'y' { print "yes\n"; } else { print "no\n"; }
+//! ```
+//!
+//! Every value is one byte, and the arithmetic wraps around like the cells
+//! do. The language has `let` and assignment, `while`, `if` with `else`, the
+//! operators `+ - * / %`, the comparisons `== != < <= > >=`, `!`, `input` to
+//! read one byte, and `print` of strings and decimal numbers. Dividing by
+//! zero gives 0, and the remainder is the dividend.
+
+use bft_types::{Program, SourceMap};
+use std::io;
+
+mod codegen;
+mod lexer;
+mod parser;
+mod print;
+
+pub use print::gen_print;
+
+/// Compiles the source into a Brainfuck program.
+///
+/// The instructions point at the statement or the expression they come from
+/// in `source`, which is named `filename` in the [`SourceMap`] of the program.
+pub fn compile(filename: String, source: &str) -> io::Result<Program> {
+    let tokens = lexer::tokenize(source)?;
+    let stmts = parser::parse(tokens)?;
+    let mut gen = codegen::Codegen::default();
+    gen.declare(&stmts)?;
+    gen.block(&stmts)?;
+
+    let mut sources = SourceMap::new();
+    sources.add(filename, source);
+    Ok(Program::from_instructions(sources, gen.ins))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile;
+    use bft_interp::VirtualMachine;
+    use bft_types::Opcode;
+
+    /// Compiles and runs the source, and returns its output.
+    fn run(source: &str, input: &[u8]) -> String {
+        let program = compile("test.bfl".to_string(), source).unwrap();
+        program.validate().unwrap();
+        let mut vm = VirtualMachine::new(0, false, program);
+        let mut input = input;
+        let mut output = Vec::new();
+        vm.interpret(&mut input, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let source = "let a = 7;\nlet b = a * 6 - 2;\nprint b, \" \", b / 7, \" \", b % 7, \" \", 0 - 1, \" \", 9 / 0;";
+        assert_eq!(run(source, b""), "40 5 5 255 0");
+    }
+
+    #[test]
+    fn comparisons() {
+        let mut source = String::from("let a = 3;\nlet b = 5;\n");
+        for op in ["==", "!=", "<", "<=", ">", ">="] {
+            source.push_str(&format!("print a {op} b, b {op} a, a {op} a;\n", op = op));
+        }
+        source.push_str("print !a, !0;");
+        assert_eq!(run(&source, b""), "00111010010101001101");
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        let source = r#"
+            let n = 3;
+            while n {
+                let twice = n * 2;
+                if n == 2 {
+                    print "two";
+                } else if n > 2 {
+                    print "big";
+                } else {
+                    print twice;
+                }
+                print "\n";
+                n = n - 1;
+            }
+        "#;
+        assert_eq!(run(source, b""), "big\ntwo\n2\n");
+    }
+
+    #[test]
+    fn echo_input() {
+        let source = "let c = input;\nwhile c != '.' {\n  print c + 1 - 1 - 48;\n  c = input;\n}";
+        assert_eq!(run(source, b"123."), "123");
+    }
+
+    #[test]
+    fn spans_point_into_the_source() {
+        let program = compile("test.bfl".to_string(), "let x = 1;\nprint x;").unwrap();
+        let output = program
+            .instructions()
+            .iter()
+            .find(|ins| ins.op == Opcode::Output)
+            .unwrap();
+        assert_eq!(output.position(), (2, 7));
+        assert_eq!(
+            program.source_map().line_text(&output.span),
+            Some("print x;")
+        );
+    }
+
+    #[test]
+    fn compile_errors() {
+        let error = |source| {
+            compile("test.bfl".to_string(), source)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(error("print y;"), "Unknown variable y at line 1 column 7.");
+        assert_eq!(
+            error("let x = 1;\nlet x = 2;"),
+            "Variable x is already defined at line 2 column 1."
+        );
+    }
+}
@@ -0,0 +1,353 @@
+//! The syntax tree of the language, and the parser building it from tokens.
+
+use crate::lexer::{error, Token};
+use bft_types::Span;
+use std::io;
+
+/// The binary operators, all on unsigned bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// An expression, its value is one byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ExprKind {
+    Number(u8),
+    Var(String),
+    /// Reads one byte of input.
+    Input,
+    /// `!x` is 1 if `x` is 0, and 0 otherwise.
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+/// An expression with the span of its first token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Expr {
+    pub(crate) kind: ExprKind,
+    pub(crate) span: Span,
+}
+
+/// One thing to print.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Item {
+    /// The bytes of a string literal.
+    Text(Vec<u8>),
+    /// The value of the expression as a decimal number.
+    Value(Expr),
+}
+
+/// A statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StmtKind {
+    Let(String, Expr),
+    Assign(String, Expr),
+    While(Expr, Vec<Stmt>),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    Print(Vec<Item>),
+}
+
+/// A statement with the span of its first token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Stmt {
+    pub(crate) kind: StmtKind,
+    pub(crate) span: Span,
+}
+
+/// Parses the tokens into a list of statements.
+pub(crate) fn parse(tokens: Vec<(Token, Span)>) -> io::Result<Vec<Stmt>> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let mut stmts = Vec::new();
+    while parser.peek().is_some() {
+        stmts.push(parser.stmt()?);
+    }
+    Ok(stmts)
+}
+
+/// A recursive descent parser over the tokens.
+struct Parser {
+    tokens: Vec<(Token, Span)>,
+    pos: usize,
+}
+
+impl Parser {
+    /// Returns the next token without taking it.
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    /// Returns the span of the next token, or of the end of the source.
+    fn span(&self) -> Span {
+        match self.tokens.get(self.pos).or_else(|| self.tokens.last()) {
+            Some((_, span)) => *span,
+            None => Span::new(1, 1, 0),
+        }
+    }
+
+    /// Takes the next token.
+    fn next(&mut self) -> io::Result<(Token, Span)> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(error("Unexpected end of the program", self.span())),
+        }
+    }
+
+    /// Takes the next token if it is the symbol.
+    fn accept(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes the symbol, or fails.
+    fn expect(&mut self, symbol: &'static str) -> io::Result<()> {
+        if self.accept(symbol) {
+            Ok(())
+        } else {
+            Err(error(&format!("Expected {}", symbol), self.span()))
+        }
+    }
+
+    /// Takes a variable name, or fails.
+    fn ident(&mut self) -> io::Result<String> {
+        match self.next()? {
+            (Token::Ident(name), _) => Ok(name),
+            (_, span) => Err(error("Expected a variable name", span)),
+        }
+    }
+
+    fn stmt(&mut self) -> io::Result<Stmt> {
+        let (token, span) = self.next()?;
+        let kind = match token {
+            Token::Let => {
+                let name = self.ident()?;
+                self.expect("=")?;
+                let value = self.expr()?;
+                self.expect(";")?;
+                StmtKind::Let(name, value)
+            }
+            Token::Ident(name) => {
+                self.expect("=")?;
+                let value = self.expr()?;
+                self.expect(";")?;
+                StmtKind::Assign(name, value)
+            }
+            Token::While => {
+                let cond = self.expr()?;
+                StmtKind::While(cond, self.block()?)
+            }
+            Token::If => return self.if_stmt(span),
+            Token::Print => {
+                let mut items = Vec::new();
+                loop {
+                    match self.peek() {
+                        Some(Token::Str(_)) => {
+                            if let (Token::Str(text), _) = self.next()? {
+                                items.push(Item::Text(text));
+                            }
+                        }
+                        _ => items.push(Item::Value(self.expr()?)),
+                    }
+                    if !self.accept(",") {
+                        break;
+                    }
+                }
+                self.expect(";")?;
+                StmtKind::Print(items)
+            }
+            _ => return Err(error("Expected a statement", span)),
+        };
+        Ok(Stmt { kind, span })
+    }
+
+    /// Parses the rest of an `if`, after the keyword at `span`.
+    fn if_stmt(&mut self, span: Span) -> io::Result<Stmt> {
+        let cond = self.expr()?;
+        let then = self.block()?;
+        let otherwise = if self.peek() == Some(&Token::Else) {
+            self.pos += 1;
+            if self.peek() == Some(&Token::If) {
+                let (_, span) = self.next()?;
+                vec![self.if_stmt(span)?]
+            } else {
+                self.block()?
+            }
+        } else {
+            Vec::new()
+        };
+        Ok(Stmt {
+            kind: StmtKind::If(cond, then, otherwise),
+            span,
+        })
+    }
+
+    fn block(&mut self) -> io::Result<Vec<Stmt>> {
+        self.expect("{")?;
+        let mut stmts = Vec::new();
+        while !self.accept("}") {
+            if self.peek().is_none() {
+                return Err(error("Expected }", self.span()));
+            }
+            stmts.push(self.stmt()?);
+        }
+        Ok(stmts)
+    }
+
+    /// Parses a comparison, the lowest precedence.
+    fn expr(&mut self) -> io::Result<Expr> {
+        let left = self.sum()?;
+        let op = match self.peek() {
+            Some(Token::Symbol("==")) => BinOp::Eq,
+            Some(Token::Symbol("!=")) => BinOp::Ne,
+            Some(Token::Symbol("<")) => BinOp::Lt,
+            Some(Token::Symbol("<=")) => BinOp::Le,
+            Some(Token::Symbol(">")) => BinOp::Gt,
+            Some(Token::Symbol(">=")) => BinOp::Ge,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.sum()?;
+        Ok(binary(op, left, right))
+    }
+
+    fn sum(&mut self) -> io::Result<Expr> {
+        let mut left = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => BinOp::Add,
+                Some(Token::Symbol("-")) => BinOp::Sub,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.term()?;
+            left = binary(op, left, right);
+        }
+    }
+
+    fn term(&mut self) -> io::Result<Expr> {
+        let mut left = self.atom()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => BinOp::Mul,
+                Some(Token::Symbol("/")) => BinOp::Div,
+                Some(Token::Symbol("%")) => BinOp::Mod,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.atom()?;
+            left = binary(op, left, right);
+        }
+    }
+
+    fn atom(&mut self) -> io::Result<Expr> {
+        let (token, span) = self.next()?;
+        let kind = match token {
+            Token::Number(value) if value <= 255 => ExprKind::Number(value as u8),
+            Token::Number(value) => {
+                return Err(error(
+                    &format!("Number {} does not fit in a cell", value),
+                    span,
+                ))
+            }
+            Token::Ident(name) => ExprKind::Var(name),
+            Token::Input => ExprKind::Input,
+            Token::Symbol("!") => ExprKind::Not(Box::new(self.atom()?)),
+            Token::Symbol("(") => {
+                let inner = self.expr()?;
+                self.expect(")")?;
+                return Ok(inner);
+            }
+            _ => return Err(error("Expected an expression", span)),
+        };
+        Ok(Expr { kind, span })
+    }
+}
+
+/// Creates a binary expression at the span of its left side.
+fn binary(op: BinOp, left: Expr, right: Expr) -> Expr {
+    let span = left.span;
+    Expr {
+        kind: ExprKind::Binary(op, Box::new(left), Box::new(right)),
+        span,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::tokenize;
+    use crate::parser::{parse, BinOp, ExprKind, StmtKind};
+
+    #[test]
+    fn precedence() {
+        let stmts = parse(tokenize("let x = 1 + 2 * 3 == 7;").unwrap()).unwrap();
+        let value = match &stmts[0].kind {
+            StmtKind::Let(name, value) if name == "x" => value,
+            other => panic!("{:?}", other),
+        };
+        let (left, right) = match &value.kind {
+            ExprKind::Binary(BinOp::Eq, left, right) => (left, right),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(right.kind, ExprKind::Number(7));
+        match &left.kind {
+            ExprKind::Binary(BinOp::Add, _, mul) => {
+                assert!(matches!(mul.kind, ExprKind::Binary(BinOp::Mul, _, _)))
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn else_if_chain() {
+        let source = "if x { print 1; } else if y { print 2; } else { print 3; }";
+        let stmts = parse(tokenize(source).unwrap()).unwrap();
+        match &stmts[0].kind {
+            StmtKind::If(_, then, otherwise) => {
+                assert_eq!(then.len(), 1);
+                assert!(
+                    matches!(otherwise[0].kind, StmtKind::If(_, _, ref last) if last.len() == 1)
+                );
+                assert_eq!(otherwise[0].span.col, 24);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |source| parse(tokenize(source).unwrap()).err().unwrap().to_string();
+        assert_eq!(
+            error("let x = 1\nprint x;"),
+            "Expected ; at line 2 column 1."
+        );
+        assert_eq!(
+            error("let x = 300;"),
+            "Number 300 does not fit in a cell at line 1 column 9."
+        );
+        assert_eq!(
+            error("while x { x = x - 1;"),
+            "Expected } at line 1 column 20."
+        );
+        assert_eq!(
+            error("print ;"),
+            "Expected an expression at line 1 column 7."
+        );
+    }
+}
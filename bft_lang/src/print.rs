@@ -0,0 +1,159 @@
+//! Generates short Brainfuck programs which print a given text.
+//!
+//! A multiplication loop first sets a few cells near the values the text
+//! needs, then every byte is printed from the cell which is the cheapest to
+//! reach and change. All the numbers of cells and loop counts are tried, and
+//! the shortest program wins.
+
+use bft_types::Program;
+
+/// Most work cells we set up before printing.
+const MAX_CELLS: usize = 8;
+
+/// Largest loop count of the multiplication loop.
+const MAX_FACTOR: usize = 20;
+
+/// Returns a program printing the text.
+///
+/// The program only uses as many cells as it sets up, and works with the
+/// usual wrapping byte cells.
+pub fn gen_print(text: &[u8]) -> Program {
+    Program::new("<gen-print>".to_string(), &gen_print_code(text))
+}
+
+/// Returns the code of the shortest program we found printing the text.
+fn gen_print_code(text: &[u8]) -> String {
+    let mut values: Vec<u8> = text.to_vec();
+    values.sort_unstable();
+    values.dedup();
+
+    // One cell stepping from byte to byte, the best for short texts
+    let mut best = print_from(&[0], 0, text, String::new());
+    for cells in 1..=MAX_CELLS.min(values.len()) {
+        let centers = clusters(&values, cells);
+        for factor in 2..=MAX_FACTOR {
+            let mut setup = "+".repeat(factor);
+            setup.push('[');
+            let mut start = Vec::with_capacity(cells + 1);
+            start.push(0);
+            for center in centers.iter() {
+                let times = (*center as usize + factor / 2) / factor;
+                setup.push('>');
+                setup.push_str(&"+".repeat(times));
+                start.push((times * factor) as u8);
+            }
+            setup.push_str(&"<".repeat(cells));
+            setup.push_str("-]");
+            let code = print_from(&start, 0, text, setup);
+            if code.len() < best.len() {
+                best = code;
+            }
+        }
+    }
+    best
+}
+
+/// Prints the text from the cells, starting with the head at `head`, and
+/// appends the code to `code`.
+///
+/// Every byte is printed from the cell which needs the fewest moves and
+/// changes, and that cell keeps the byte for the next ones.
+fn print_from(start: &[u8], mut head: usize, text: &[u8], mut code: String) -> String {
+    let mut cells = start.to_vec();
+    for byte in text {
+        let cost = |cell: usize, value: u8| head.abs_diff(cell) + change_cost(value, *byte);
+        let (cell, _) = cells
+            .iter()
+            .enumerate()
+            .min_by_key(|(cell, value)| cost(*cell, **value))
+            .expect("there is always a cell");
+        let step = if cell > head { '>' } else { '<' };
+        code.extend(std::iter::repeat_n(step, head.abs_diff(cell)));
+        head = cell;
+
+        let up = byte.wrapping_sub(cells[cell]);
+        if up <= 128 {
+            code.extend(std::iter::repeat_n('+', up as usize));
+        } else {
+            code.extend(std::iter::repeat_n('-', 256 - up as usize));
+        }
+        code.push('.');
+        cells[cell] = *byte;
+    }
+    code
+}
+
+/// Number of `+` or `-` to change the cell from `from` to `to`.
+fn change_cost(from: u8, to: u8) -> usize {
+    let up = to.wrapping_sub(from) as usize;
+    up.min(256 - up)
+}
+
+/// Splits the sorted distinct values into `count` groups of neighbours, and
+/// returns the middle of every group.
+fn clusters(values: &[u8], count: usize) -> Vec<u8> {
+    // Cut at the widest gaps between the neighbouring values
+    let mut gaps: Vec<usize> = (1..values.len()).collect();
+    gaps.sort_by_key(|index| std::cmp::Reverse(values[*index] - values[*index - 1]));
+    let mut cuts: Vec<usize> = gaps.into_iter().take(count - 1).collect();
+    cuts.sort_unstable();
+    cuts.push(values.len());
+
+    let mut start = 0;
+    cuts.into_iter()
+        .map(|end| {
+            let group = &values[start..end];
+            start = end;
+            let (low, high) = (group[0] as usize, group[group.len() - 1] as usize);
+            ((low + high) / 2) as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::print::{clusters, gen_print};
+    use bft_interp::VirtualMachine;
+
+    /// Generates the program, checks it and returns its output and length.
+    fn run(text: &[u8]) -> (Vec<u8>, usize) {
+        let program = gen_print(text);
+        program.validate().unwrap();
+        let length = program.instructions().len();
+        let mut vm = VirtualMachine::new(0, false, program);
+        let mut output = Vec::new();
+        vm.interpret(&mut std::io::empty(), &mut output).unwrap();
+        (output, length)
+    }
+
+    #[test]
+    fn prints_the_text() {
+        for text in [
+            &b"Hello, World!\n"[..],
+            b"",
+            b"a",
+            b"\x00\xff\x80 ~",
+            b"The quick brown fox jumps over the lazy dog.",
+        ] {
+            assert_eq!(run(text).0, text);
+        }
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(run(&all).0, all);
+    }
+
+    #[test]
+    fn shorter_than_counting() {
+        let text = b"Hello, World!\n";
+        let (_, length) = run(text);
+        // Counting up from zero for every byte
+        let naive: usize = text.iter().map(|byte| *byte as usize + 1 + 3).sum();
+        assert!(length < naive / 4, "{} instructions", length);
+        assert!(length < 150, "{} instructions", length);
+    }
+
+    #[test]
+    fn groups_of_values() {
+        assert_eq!(clusters(&[10, 11, 12, 100, 101, 200], 3), [11, 100, 200]);
+        assert_eq!(clusters(&[10, 20], 1), [15]);
+    }
+}
@@ -0,0 +1,513 @@
+//! Abstract interpretation of a program, to find out without running it how
+//! far the head goes and which loops can never end.
+//!
+//! The analysis follows every path of the program at once. The head is an
+//! interval of cells, and a cell holds either a known value, a value known to
+//! be nonzero, or anything. Loops run until nothing changes any more, and what
+//! still grows after a few rounds is widened so the analysis always ends.
+//! What it reports holds for every run on a zero tape with the head on the
+//! first cell, whatever the input.
+
+use crate::{Instruction, Opcode, Span};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Rounds of a loop before what still grows is widened.
+const WIDEN_AFTER: usize = 3;
+
+/// Widest head interval whose cells are still followed one by one.
+const MAX_RANGE: u64 = 64;
+
+/// What the analysis found out about a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    /// Every loop of the program, in the order of the code.
+    pub loops: Vec<LoopReport>,
+    /// Highest cell the head can reach, `None` when it is not bounded.
+    pub max_cell: Option<usize>,
+    /// The `<` instructions which can move the head left of the first cell.
+    pub underflows: Vec<Span>,
+}
+
+impl Analysis {
+    /// Number of cells the program can need, `None` when it is not bounded.
+    pub fn tape_size(&self) -> Option<usize> {
+        self.max_cell.map(|cell| cell + 1)
+    }
+
+    /// Returns the loops which never end once the program gets to them.
+    pub fn endless_loops(&self) -> impl Iterator<Item = &LoopReport> {
+        self.loops.iter().filter(|report| report.never_ends)
+    }
+}
+
+/// What the analysis found out about one loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopReport {
+    /// Span of the `[`.
+    pub start: Span,
+    /// Span of the `]`.
+    pub end: Span,
+    /// How far every round of the loop moves the head, `None` when it depends
+    /// on the data.
+    pub shift: Option<isize>,
+    /// If the program can get to the loop.
+    pub reached: bool,
+    /// The program can get to the loop, and then it never ends.
+    pub never_ends: bool,
+}
+
+impl LoopReport {
+    /// Tells if every round of the loop leaves the head where it started.
+    pub fn is_balanced(&self) -> bool {
+        self.shift == Some(0)
+    }
+}
+
+/// Analyses the instructions, which must have matching brackets, for cells
+/// holding values up to `cell_max`.
+pub(crate) fn analyze(ins: &[Instruction], cell_max: u64) -> Analysis {
+    let ins: Vec<Instruction> = ins
+        .iter()
+        .filter(|ins| !matches!(ins.op, Opcode::Comment(_)))
+        .copied()
+        .collect();
+    let jumps = match_brackets(&ins);
+    let mut analyzer = Analyzer {
+        ins: &ins,
+        jumps: &jumps,
+        modulus: cell_max as u128 + 1,
+        exits: BTreeMap::new(),
+        max_cell: Some(0),
+        underflows: BTreeSet::new(),
+    };
+    analyzer.run(0, ins.len(), Some(State::zero()));
+
+    let loops = (0..ins.len())
+        .filter(|open| ins[*open].op == Opcode::JumpForward)
+        .map(|open| {
+            let exits = analyzer.exits.get(&open);
+            LoopReport {
+                start: ins[open].span,
+                end: ins[jumps[open]].span,
+                shift: shift(&ins, &jumps, open),
+                reached: exits.is_some(),
+                never_ends: exits == Some(&false),
+            }
+        })
+        .collect();
+    Analysis {
+        loops,
+        max_cell: analyzer.max_cell.map(|cell| cell as usize),
+        underflows: analyzer
+            .underflows
+            .iter()
+            .map(|pos| ins[*pos].span)
+            .collect(),
+    }
+}
+
+/// Returns the index of the matching bracket or parenthesis of every bracket
+/// and parenthesis.
+fn match_brackets(ins: &[Instruction]) -> Vec<usize> {
+    let mut jumps = vec![0; ins.len()];
+    let mut stack = Vec::new();
+    for (pos, ins) in ins.iter().enumerate() {
+        match ins.op {
+            Opcode::JumpForward | Opcode::ProcedureStart => stack.push(pos),
+            Opcode::JumpBack | Opcode::ProcedureEnd => {
+                if let Some(open) = stack.pop() {
+                    jumps[open] = pos;
+                    jumps[pos] = open;
+                }
+            }
+            _ => (),
+        }
+    }
+    jumps
+}
+
+/// Returns how far a round of the loop opened at `open` moves the head, if
+/// it is always the same.
+fn shift(ins: &[Instruction], jumps: &[usize], open: usize) -> Option<isize> {
+    let mut moved = 0;
+    let mut pos = open + 1;
+    while pos < jumps[open] {
+        match ins[pos].op {
+            Opcode::IncrementDP => moved += 1,
+            Opcode::DecrementDP => moved -= 1,
+            Opcode::JumpForward if shift(ins, jumps, pos) == Some(0) => pos = jumps[pos],
+            // A procedure is only defined here
+            Opcode::ProcedureStart => pos = jumps[pos],
+            Opcode::JumpForward | Opcode::CallProcedure | Opcode::MoveUp | Opcode::MoveDown => {
+                return None
+            }
+            _ => (),
+        }
+        pos += 1;
+    }
+    Some(moved)
+}
+
+/// What the analysis knows of the value of a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Known(u128),
+    NonZero,
+    Unknown,
+}
+
+impl Value {
+    /// The value of a cell after `+`, or `-` when `up` is false.
+    fn step(self, up: bool, modulus: u128) -> Value {
+        match self {
+            Value::Known(value) if up => Value::Known((value + 1) % modulus),
+            Value::Known(value) => Value::Known((value + modulus - 1) % modulus),
+            _ => Value::Unknown,
+        }
+    }
+
+    fn can_be_zero(self) -> bool {
+        matches!(self, Value::Known(0) | Value::Unknown)
+    }
+
+    /// The value holding both values.
+    fn join(self, other: Value) -> Value {
+        if self == other {
+            self
+        } else if !self.can_be_zero() && !other.can_be_zero() {
+            Value::NonZero
+        } else {
+            Value::Unknown
+        }
+    }
+}
+
+/// What the analysis knows of the machine at one point of the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    /// Lowest cell the head can be on.
+    lo: u64,
+    /// Highest cell the head can be on, `None` when it is not bounded.
+    hi: Option<u64>,
+    /// The cells whose value may not be `rest`.
+    cells: BTreeMap<u64, Value>,
+    /// Value of the other cells.
+    rest: Value,
+}
+
+impl State {
+    /// The machine when the program starts.
+    fn zero() -> Self {
+        State {
+            lo: 0,
+            hi: Some(0),
+            cells: BTreeMap::new(),
+            rest: Value::Known(0),
+        }
+    }
+
+    /// Nothing is known.
+    fn unknown() -> Self {
+        State {
+            lo: 0,
+            hi: None,
+            cells: BTreeMap::new(),
+            rest: Value::Unknown,
+        }
+    }
+
+    fn get(&self, cell: u64) -> Value {
+        *self.cells.get(&cell).unwrap_or(&self.rest)
+    }
+
+    /// Returns the cells the head can be on, if there are few enough of them
+    /// to follow one by one.
+    fn range(&self) -> Option<(u64, u64)> {
+        match self.hi {
+            Some(hi) if hi - self.lo <= MAX_RANGE => Some((self.lo, hi)),
+            _ => None,
+        }
+    }
+
+    /// Changes the cell under the head with `change`.
+    fn update(&mut self, change: impl Fn(Value) -> Value) {
+        match self.range() {
+            Some((lo, hi)) if lo == hi => {
+                let value = change(self.get(lo));
+                self.cells.insert(lo, value);
+            }
+            // Any of the cells can change, or stay the same
+            Some((lo, hi)) => {
+                for cell in lo..=hi {
+                    let value = self.get(cell);
+                    self.cells.insert(cell, value.join(change(value)));
+                }
+            }
+            None => {
+                for (_, value) in self.cells.range_mut(self.lo..) {
+                    *value = value.join(change(*value));
+                }
+                self.rest = self.rest.join(change(self.rest));
+            }
+        }
+    }
+
+    /// Keeps the paths where the cell under the head is zero, or not zero,
+    /// `None` when there is none.
+    fn assume(mut self, zero: bool) -> Option<State> {
+        let (mut lo, mut hi) = match self.range() {
+            Some(range) => range,
+            None => return Some(self),
+        };
+        let excluded = |value: Value| {
+            if zero {
+                !value.can_be_zero()
+            } else {
+                value == Value::Known(0)
+            }
+        };
+        while lo <= hi && excluded(self.get(lo)) {
+            lo += 1;
+        }
+        while lo <= hi && excluded(self.get(hi)) {
+            hi -= 1;
+        }
+        if lo > hi {
+            return None;
+        }
+        self.lo = lo;
+        self.hi = Some(hi);
+        if lo == hi {
+            let value = match self.get(lo) {
+                _ if zero => Value::Known(0),
+                Value::Unknown => Value::NonZero,
+                value => value,
+            };
+            self.cells.insert(lo, value);
+        }
+        Some(self)
+    }
+
+    /// The state holding both states.
+    fn join(&self, other: &State) -> State {
+        let mut cells = BTreeMap::new();
+        for cell in self.cells.keys().chain(other.cells.keys()) {
+            cells.insert(*cell, self.get(*cell).join(other.get(*cell)));
+        }
+        State {
+            lo: self.lo.min(other.lo),
+            hi: match (self.hi, other.hi) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            },
+            cells,
+            rest: self.rest.join(other.rest),
+        }
+    }
+
+    /// Gives up on what still changes from `self` to `next`, so loops reach
+    /// a state which does not change any more.
+    fn widen(&self, next: &State) -> State {
+        let mut widened = next.clone();
+        if next.lo < self.lo {
+            widened.lo = 0;
+        }
+        if next.hi.is_none() || self.hi.is_none() || next.hi > self.hi {
+            widened.hi = None;
+        }
+        for (cell, value) in widened.cells.iter_mut() {
+            if *value != self.get(*cell) {
+                *value = Value::Unknown;
+            }
+        }
+        if next.rest != self.rest {
+            widened.rest = Value::Unknown;
+        }
+        widened
+    }
+}
+
+struct Analyzer<'a> {
+    ins: &'a [Instruction],
+    jumps: &'a [usize],
+    /// Number of values a cell can hold.
+    modulus: u128,
+    /// If each loop the program gets to can end, by the index of its `[`.
+    exits: BTreeMap<usize, bool>,
+    /// Highest cell the head can reach so far, `None` when not bounded.
+    max_cell: Option<u64>,
+    /// Index of the `<` which can move the head left of the first cell.
+    underflows: BTreeSet<usize>,
+}
+
+impl Analyzer<'_> {
+    /// Runs the instructions from `from` up to `to` on the state, and returns
+    /// the state after them, `None` when they never get there.
+    fn run(&mut self, from: usize, to: usize, state: Option<State>) -> Option<State> {
+        let mut state = state?;
+        let mut pos = from;
+        while pos < to {
+            match self.ins[pos].op {
+                Opcode::IncrementDP => {
+                    state.lo += 1;
+                    state.hi = state.hi.map(|hi| hi + 1);
+                    self.reach(&state);
+                }
+                Opcode::DecrementDP => {
+                    if state.lo == 0 {
+                        self.underflows.insert(pos);
+                        if state.hi == Some(0) {
+                            return None;
+                        }
+                    }
+                    // Only the paths which did not fall off the tape go on
+                    state.lo = state.lo.saturating_sub(1);
+                    state.hi = state.hi.map(|hi| hi - 1);
+                }
+                Opcode::IncrementByte => {
+                    let modulus = self.modulus;
+                    state.update(|value| value.step(true, modulus));
+                }
+                Opcode::DecrementByte => {
+                    let modulus = self.modulus;
+                    state.update(|value| value.step(false, modulus));
+                }
+                Opcode::Input
+                | Opcode::LoadStorage
+                | Opcode::ShiftRight
+                | Opcode::ShiftLeft
+                | Opcode::BitNot
+                | Opcode::BitXor
+                | Opcode::BitAnd
+                | Opcode::BitOr => state.update(|_| Value::Unknown),
+                Opcode::EndProgram => return None,
+                Opcode::CallProcedure | Opcode::MoveUp | Opcode::MoveDown => {
+                    state = State::unknown();
+                    self.reach(&state);
+                }
+                Opcode::JumpForward => {
+                    let close = self.jumps[pos];
+                    state = self.run_loop(pos, close, state)?;
+                    pos = close;
+                }
+                Opcode::ProcedureStart => {
+                    // The procedure can be called from anywhere
+                    let close = self.jumps[pos];
+                    self.run(pos + 1, close, Some(State::unknown()));
+                    pos = close;
+                }
+                _ => (),
+            }
+            pos += 1;
+        }
+        Some(state)
+    }
+
+    /// Runs the loop from `open` to `close` until its state does not change
+    /// any more, and returns the state after it.
+    fn run_loop(&mut self, open: usize, close: usize, entry: State) -> Option<State> {
+        let mut test = entry;
+        let mut rounds = 0;
+        loop {
+            let body = test.clone().assume(false);
+            let mut next = match self.run(open + 1, close, body) {
+                Some(after) => test.join(&after),
+                None => test.clone(),
+            };
+            if rounds >= WIDEN_AFTER {
+                next = test.widen(&next);
+                self.reach(&next);
+            }
+            if next == test {
+                break;
+            }
+            test = next;
+            rounds += 1;
+        }
+        let exit = test.assume(true);
+        *self.exits.entry(open).or_insert(false) |= exit.is_some();
+        exit
+    }
+
+    /// Records how far the head of the state can go.
+    fn reach(&mut self, state: &State) {
+        self.max_cell = match (self.max_cell, state.hi) {
+            (Some(max), Some(hi)) => Some(max.max(hi)),
+            _ => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Program;
+
+    fn program(code: &str) -> Program {
+        Program::new("test.bf".to_string(), code)
+    }
+
+    #[test]
+    fn tape_extent() {
+        let analysis = program(">>>+<<").analyze().unwrap();
+        assert_eq!(analysis.max_cell, Some(3));
+        assert_eq!(analysis.tape_size(), Some(4));
+        assert!(analysis.underflows.is_empty());
+        // Balanced loops keep the head in place, the clear loop is bounded
+        let hello = "++++++++++[>+>++++>+++++++>++++++++++<<<<-]>>>++.>+.[-]<<<[>+<-]";
+        assert_eq!(program(hello).analyze().unwrap().max_cell, Some(4));
+        // The head goes as far as the input says
+        assert_eq!(program(",[>,]").analyze().unwrap().max_cell, None);
+        assert_eq!(program("+[>+]").analyze().unwrap().max_cell, None);
+        // A scan stops at the first zero cell
+        assert_eq!(program(">>+<+[>]").analyze().unwrap().max_cell, Some(3));
+    }
+
+    #[test]
+    fn underflows() {
+        let analysis = program("><<").analyze().unwrap();
+        let positions: Vec<(usize, usize)> = analysis
+            .underflows
+            .iter()
+            .map(|span| (span.line, span.col))
+            .collect();
+        assert_eq!(positions, [(1, 3)]);
+        let analysis = program(",[<,]").analyze().unwrap();
+        assert_eq!(analysis.underflows.len(), 1);
+        // The scan stops on the zero cell before falling off the tape
+        assert!(program(">+[<]").analyze().unwrap().underflows.is_empty());
+    }
+
+    #[test]
+    fn loops() {
+        let analysis = program("+[-]\n,[>+<-]>[>]\n,[[>]<]").analyze().unwrap();
+        let shifts: Vec<Option<isize>> = analysis.loops.iter().map(|l| l.shift).collect();
+        assert_eq!(shifts, [Some(0), Some(0), Some(1), None, Some(1)]);
+        assert!(analysis.loops[0].is_balanced());
+        assert!(analysis.loops.iter().all(|l| l.reached && !l.never_ends));
+        let last = analysis.loops[3];
+        assert_eq!((last.start.line, last.start.col), (3, 2));
+        assert_eq!((last.end.line, last.end.col), (3, 7));
+
+        // The cell under the head stays nonzero
+        let analysis = program("+[>+<]>.").analyze().unwrap();
+        assert!(analysis.loops[0].never_ends);
+        assert_eq!(analysis.endless_loops().count(), 1);
+        // The code after an endless loop is never reached
+        let analysis = program("+[]+[-]").analyze().unwrap();
+        assert!(analysis.loops[0].never_ends);
+        assert!(!analysis.loops[1].reached);
+        // A loop on a zero cell never runs
+        let analysis = program("[+]").analyze().unwrap();
+        assert!(analysis.loops[0].reached && !analysis.loops[0].never_ends);
+    }
+
+    #[test]
+    fn cell_width() {
+        let code = format!("{}[]", "+".repeat(256));
+        let analysis = program(&code).analyze().unwrap();
+        assert!(!analysis.loops[0].never_ends);
+        let analysis = program(&code).analyze_cells(u16::MAX as u64).unwrap();
+        assert!(analysis.loops[0].never_ends);
+        assert!(program("[[]").analyze().is_err());
+    }
+}
@@ -0,0 +1,762 @@
+//! Lexers for Brainfuck and the dialects which are straight token substitutions of it.
+//!
+//! A [`Dialect`] is a list of tokens, and the Brainfuck character each of them
+//! stands for. A space inside a token matches any amount of whitespace in the
+//! source, so `Ook. Ook?` also matches when the two words are on different lines.
+
+use crate::{Instruction, Opcode, Program, Span};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The Brainfuck characters, in the order we list them in the dialects.
+const BRAINFUCK: [char; 8] = ['>', '<', '+', '-', '.', ',', '[', ']'];
+
+/// The Ook! style pairs for `> < + - . , [ ]`, the word is added later.
+const PAIRS: [(char, char); 8] = [
+    ('.', '?'),
+    ('?', '.'),
+    ('.', '.'),
+    ('!', '!'),
+    ('!', '.'),
+    ('.', '!'),
+    ('!', '?'),
+    ('?', '!'),
+];
+
+/// The character which separates the program from its input.
+const INPUT_SEPARATOR: char = '!';
+
+/// Opt-in instruction sets and conventions on top of plain Brainfuck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    /// Extended Brainfuck Type I: `@ $ ! } { ~ ^ & |`.
+    Ebf1,
+    /// `#` dumps the state of the machine to stderr.
+    Debug,
+    /// The first `!` ends the program, and the text after it is the input.
+    /// This wins over the `!` of Extended Type I.
+    Separator,
+    /// pbrain procedures: `(`, `)` and `:`.
+    Pbrain,
+    /// Two dimensional memory: `^` moves the head up and `v` down.
+    /// This wins over the `^` of Extended Type I.
+    Grid,
+    /// Macros, constants and repetitions expanded before lexing, see
+    /// [`Program::with_macros`]. The `{` and `}` groups win over the shifts of
+    /// Extended Type I.
+    Macros,
+}
+
+impl Extension {
+    /// The characters this extension adds as instructions.
+    pub fn symbols(&self) -> &'static [char] {
+        match self {
+            Extension::Ebf1 => &['@', '$', '!', '}', '{', '~', '^', '&', '|'],
+            Extension::Debug => &['#'],
+            Extension::Separator => &[],
+            Extension::Pbrain => &['(', ')', ':'],
+            Extension::Grid => &['^', 'v'],
+            Extension::Macros => &[],
+        }
+    }
+}
+
+impl FromStr for Extension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ebf1" => Ok(Extension::Ebf1),
+            "debug" => Ok(Extension::Debug),
+            "separator" => Ok(Extension::Separator),
+            "pbrain" => Ok(Extension::Pbrain),
+            "2d" => Ok(Extension::Grid),
+            "macros" => Ok(Extension::Macros),
+            _ => Err(format!(
+                "Unknown extension {}, use one of ebf1, debug, separator, pbrain, 2d or macros.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Extension::Ebf1 => write!(f, "ebf1"),
+            Extension::Debug => write!(f, "debug"),
+            Extension::Separator => write!(f, "separator"),
+            Extension::Pbrain => write!(f, "pbrain"),
+            Extension::Grid => write!(f, "2d"),
+            Extension::Macros => write!(f, "macros"),
+        }
+    }
+}
+
+/// A token substitution dialect of Brainfuck.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dialect {
+    /// Name of the dialect.
+    name: String,
+    /// The tokens and the Brainfuck characters they stand for, longest token first.
+    tokens: Vec<(String, char)>,
+    /// The extensions added with [`Dialect::with_extension`].
+    extensions: Vec<Extension>,
+}
+
+/// Layout of a custom dialect file.
+///
+/// ```toml
+/// name = "moo"
+/// extensions = ["debug"]
+///
+/// [tokens]
+/// "MOo" = ">"
+/// "mOo" = "<"
+/// "OOM" = "#"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DialectFile {
+    /// Name of the dialect, by default the file name.
+    name: Option<String>,
+    /// Extensions whose characters the tokens may map to.
+    #[serde(default)]
+    extensions: Vec<String>,
+    /// Token to Brainfuck character mapping.
+    tokens: BTreeMap<String, String>,
+}
+
+impl Dialect {
+    /// Creates a new dialect from a list of `(token, brainfuck character)` pairs.
+    ///
+    /// Returns an error if a token is empty, or the character is not a
+    /// Brainfuck instruction.
+    pub fn new(name: &str, tokens: Vec<(String, char)>) -> io::Result<Self> {
+        Dialect::with_extensions(name, tokens, &[])
+    }
+
+    /// Same as [`Dialect::new`], but the tokens may also stand for the
+    /// characters of the extensions, which are added with
+    /// [`Dialect::with_extension`].
+    pub fn with_extensions(
+        name: &str,
+        tokens: Vec<(String, char)>,
+        extensions: &[Extension],
+    ) -> io::Result<Self> {
+        for (token, ch) in tokens.iter() {
+            if token.trim().is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Empty token in dialect {}.", name),
+                ));
+            }
+            let allowed = BRAINFUCK.contains(ch)
+                || extensions
+                    .iter()
+                    .any(|extension| extension.symbols().contains(ch));
+            if !allowed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Token {} in dialect {} maps to {}, which is not an instruction.",
+                        token, name, ch
+                    ),
+                ));
+            }
+        }
+        let mut tokens = tokens;
+        // Longest token first, so that it wins over its own prefixes.
+        tokens.sort_by_key(|(token, _)| std::cmp::Reverse(token.chars().count()));
+        let dialect = Dialect {
+            name: name.to_string(),
+            tokens,
+            extensions: Vec::new(),
+        };
+        Ok(extensions.iter().fold(dialect, |dialect, extension| {
+            dialect.with_extension(*extension)
+        }))
+    }
+
+    /// Plain Brainfuck, every character is a token.
+    pub fn brainfuck() -> Self {
+        let tokens = BRAINFUCK.iter().map(|ch| (ch.to_string(), *ch)).collect();
+        Dialect {
+            name: "bf".to_string(),
+            tokens,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Ook!, where every instruction is a pair like `Ook. Ook?`.
+    pub fn ook() -> Self {
+        Dialect::pairs("ook", "Ook")
+    }
+
+    /// Blub, which is Ook! with `Blub` as the word.
+    pub fn blub() -> Self {
+        Dialect::pairs("blub", "Blub")
+    }
+
+    /// Creates an Ook! like dialect with the given word.
+    fn pairs(name: &str, word: &str) -> Self {
+        let tokens = PAIRS
+            .iter()
+            .zip(BRAINFUCK.iter())
+            .map(|((first, second), ch)| (format!("{}{} {}{}", word, first, word, second), *ch))
+            .collect();
+        Dialect {
+            name: name.to_string(),
+            tokens,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Adds the characters of the extension as tokens of this dialect.
+    pub fn with_extension(mut self, extension: Extension) -> Self {
+        if !self.extensions.contains(&extension) {
+            self.extensions.push(extension);
+        }
+        for ch in extension.symbols() {
+            if self.token_for(*ch).is_none() {
+                self.tokens.push((ch.to_string(), *ch));
+            }
+        }
+        // Longest token first, so that it wins over its own prefixes.
+        self.tokens
+            .sort_by_key(|(token, _)| std::cmp::Reverse(token.chars().count()));
+        self
+    }
+
+    /// Tells us if the extension was added with [`Dialect::with_extension`].
+    pub fn has_extension(&self, extension: Extension) -> bool {
+        self.extensions.contains(&extension)
+    }
+
+    /// Returns the builtin dialect with this name: `bf`, `ook` or `blub`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "bf" | "brainfuck" => Some(Dialect::brainfuck()),
+            "ook" | "ook!" => Some(Dialect::ook()),
+            "blub" => Some(Dialect::blub()),
+            _ => None,
+        }
+    }
+
+    /// Reads a custom dialect from a TOML file mapping every token to a
+    /// Brainfuck character.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = fs::read_to_string(&path)?;
+        let name = path
+            .as_ref()
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("custom");
+        Dialect::from_toml(name, &content)
+    }
+
+    /// Parses a custom dialect from TOML text, see [`Dialect::from_file`].
+    pub fn from_toml(name: &str, content: &str) -> io::Result<Self> {
+        let file: DialectFile = toml::from_str(content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let name = file.name.unwrap_or_else(|| name.to_string());
+        let extensions = file
+            .extensions
+            .iter()
+            .map(|extension| extension.parse::<Extension>())
+            .collect::<Result<Vec<Extension>, String>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut tokens = Vec::new();
+        for (token, value) in file.tokens {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(ch), None) => tokens.push((token, ch)),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Token {} in dialect {} must map to a single character, not {}.",
+                            token, name, value
+                        ),
+                    ));
+                }
+            }
+        }
+        Dialect::with_extensions(&name, tokens, &extensions)
+    }
+
+    /// Returns the name of the dialect.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Splits the source into instructions.
+    ///
+    /// Every token becomes the instruction it stands for, with the span of its
+    /// first character in the source. Any other character is kept as a comment,
+    /// except the line endings. The spans point into the file `0`.
+    pub fn lex(&self, content: &str) -> Vec<Instruction> {
+        self.lex_with_input(0, content).0
+    }
+
+    /// Same as [`Dialect::lex`], but the spans point into the file `file_id`,
+    /// and with the [`Extension::Separator`] it also returns the input after
+    /// the first `!` which is not part of a token.
+    pub fn lex_with_input(
+        &self,
+        file_id: usize,
+        content: &str,
+    ) -> (Vec<Instruction>, Option<String>) {
+        self.lex_source(file_id, content, self.has_extension(Extension::Separator))
+    }
+
+    /// Same as [`Dialect::lex_with_input`], but a `!` is never the separator,
+    /// for a program already split from its input by [`Dialect::split_input`].
+    pub(crate) fn lex_program(&self, file_id: usize, content: &str) -> Vec<Instruction> {
+        self.lex_source(file_id, content, false).0
+    }
+
+    /// Lexes the source, and stops at the first `!` if `separator` is set.
+    fn lex_source(
+        &self,
+        file_id: usize,
+        content: &str,
+        separator: bool,
+    ) -> (Vec<Instruction>, Option<String>) {
+        let chars: Vec<char> = content.chars().collect();
+        let mut ins = Vec::new();
+        let mut linenumber = 1;
+        let mut charnumber = 1;
+        let mut byte_offset = 0;
+        let mut pos = 0;
+
+        while pos < chars.len() {
+            if separator && chars[pos] == INPUT_SEPARATOR {
+                let input = chars[pos + 1..].iter().collect();
+                return (ins, Some(input));
+            }
+            let span = Span::new(linenumber, charnumber, byte_offset).in_file(file_id);
+            let (length, instruction) = match self.match_token(&chars[pos..]) {
+                Some((length, ch)) => (length, Some(self.instruction(span, ch))),
+                None => {
+                    let ch = chars[pos];
+                    let line_end = ch == '\n' || (ch == '\r' && chars.get(pos + 1) == Some(&'\n'));
+                    let comment = if line_end {
+                        None
+                    } else {
+                        Some(Instruction::new(Opcode::Comment(ch), span))
+                    };
+                    (1, comment)
+                }
+            };
+            if let Some(instruction) = instruction {
+                ins.push(instruction);
+            }
+            for ch in &chars[pos..pos + length] {
+                byte_offset += ch.len_utf8();
+                if *ch == '\n' {
+                    linenumber += 1;
+                    charnumber = 1;
+                } else {
+                    charnumber += 1;
+                }
+            }
+            pos += length;
+        }
+        (ins, None)
+    }
+
+    /// Splits `content` at the separator into the program and the input, see
+    /// [`Dialect::lex_with_input`].
+    ///
+    /// A `!` on the lines `directive` accepts is not the separator, those
+    /// lines are read before the lexer sees the rest.
+    pub(crate) fn split_input<'a>(
+        &self,
+        content: &'a str,
+        directive: impl Fn(&str) -> bool,
+    ) -> (&'a str, Option<&'a str>) {
+        let mut blanked = String::with_capacity(content.len());
+        for line in content.split_inclusive('\n') {
+            if directive(line) {
+                blanked.push_str(&" ".repeat(line.trim_end().len()));
+                blanked.push_str(&line[line.trim_end().len()..]);
+            } else {
+                blanked.push_str(line);
+            }
+        }
+        match self.lex_with_input(0, &blanked).1 {
+            Some(input) => {
+                let input_start = content.len() - input.len();
+                let program_end = input_start - INPUT_SEPARATOR.len_utf8();
+                (&content[..program_end], Some(&content[input_start..]))
+            }
+            None => (content, None),
+        }
+    }
+
+    /// Creates the instruction for a matched token, the extensions decide
+    /// between the instructions sharing a character.
+    fn instruction(&self, span: Span, ch: char) -> Instruction {
+        let op = match ch {
+            '^' if self.extensions.contains(&Extension::Grid) => Opcode::MoveUp,
+            _ => Opcode::from(ch),
+        };
+        Instruction::new(op, span)
+    }
+
+    /// Writes the program in the surface syntax of this dialect.
+    ///
+    /// The line structure of the original source is kept, the instructions of
+    /// the included files stay on the line of their include. With `keep_comments`
+    /// the comment text is copied too, except the characters which would read as
+    /// an instruction in this dialect. Returns an error if the program has an
+    /// instruction this dialect has no token for, or an input while this
+    /// dialect has no [`Extension::Separator`].
+    pub fn emit(&self, program: &Program, keep_comments: bool) -> io::Result<String> {
+        // Tokens made of words need a space between them.
+        let separator = if self
+            .tokens
+            .iter()
+            .any(|(token, _)| token.chars().count() > 1)
+        {
+            " "
+        } else {
+            ""
+        };
+        let mut output = String::new();
+        let mut comment = String::new();
+        let mut line = 1;
+        let mut after_token = false;
+
+        for ins in program.instructions() {
+            // Only the lines of the main file, the lines of an included file
+            // say nothing about where it is
+            let linenumber = ins.position().0;
+            if ins.span.file_id == 0 && linenumber > line {
+                output.push_str(&self.escape(&comment));
+                comment.clear();
+                for _ in line..linenumber {
+                    output.push('\n');
+                }
+                line = linenumber;
+                after_token = false;
+            }
+            match (ins.op, ins.as_char()) {
+                (Opcode::Comment(ch), _) => {
+                    if keep_comments {
+                        comment.push(ch);
+                    }
+                }
+                (_, Some(ch)) => {
+                    let token = self.token_for(ch).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Dialect {} has no token for {} at {}.",
+                                self.name,
+                                ch,
+                                program.describe(&ins.span)
+                            ),
+                        )
+                    })?;
+                    if !comment.is_empty() {
+                        output.push_str(&self.escape(&comment));
+                        comment.clear();
+                    } else if after_token {
+                        output.push_str(separator);
+                    }
+                    output.push_str(token);
+                    after_token = true;
+                }
+                (_, None) => (),
+            }
+        }
+        output.push_str(&self.escape(&comment));
+        if let Some(input) = program.input() {
+            if !self.has_extension(Extension::Separator) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Dialect {} has no separator for the input of the program.",
+                        self.name
+                    ),
+                ));
+            }
+            output.push(INPUT_SEPARATOR);
+            output.push_str(input);
+        }
+        Ok(output)
+    }
+
+    /// Returns the token for the Brainfuck character.
+    fn token_for(&self, ch: char) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|(_, token_ch)| *token_ch == ch)
+            .map(|(token, _)| token.as_str())
+    }
+
+    /// Removes the parts of the comment which would read as instructions in
+    /// this dialect.
+    fn escape(&self, comment: &str) -> String {
+        self.lex(comment)
+            .iter()
+            .filter_map(|ins| match ins.op {
+                Opcode::Comment(ch) => Some(ch),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Finds the token at the start of `text`, and returns its length in
+    /// characters along with the Brainfuck character it stands for.
+    fn match_token(&self, text: &[char]) -> Option<(usize, char)> {
+        self.tokens
+            .iter()
+            .find_map(|(token, ch)| match_at(token, text).map(|length| (length, *ch)))
+    }
+}
+
+/// Matches `token` at the start of `text`, where a space in the token matches
+/// one or more whitespace characters. Returns the length of the match.
+fn match_at(token: &str, text: &[char]) -> Option<usize> {
+    let mut pos = 0;
+    for expected in token.chars() {
+        if expected == ' ' {
+            if !text.get(pos)?.is_whitespace() {
+                return None;
+            }
+            while text.get(pos).is_some_and(|ch| ch.is_whitespace()) {
+                pos += 1;
+            }
+        } else {
+            if *text.get(pos)? != expected {
+                return None;
+            }
+            pos += 1;
+        }
+    }
+    Some(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dialect::{Dialect, Extension};
+    use crate::{Instruction, Opcode, Program, SourceMap, Span};
+
+    fn op_at(ins: &Instruction) -> (Opcode, usize, usize) {
+        (ins.op, ins.span.line, ins.span.col)
+    }
+
+    #[test]
+    fn ook_pairs() {
+        let input = "Ook. Ook. Ook! Ook?\nOok. Ook?  Ook!\nOok! Ook? Ook!";
+        let p = Program::with_dialect("test.ook".to_string(), input, &Dialect::ook()).unwrap();
+        assert_eq!(p.to_string(), "+[>-]");
+        let ins: Vec<&Instruction> = p
+            .instructions()
+            .iter()
+            .filter(|ins| !matches!(ins.op, Opcode::Comment(_)))
+            .collect();
+        assert_eq!(op_at(ins[0]), (Opcode::IncrementByte, 1, 1));
+        assert_eq!(op_at(ins[1]), (Opcode::JumpForward, 1, 11));
+        assert_eq!(op_at(ins[2]), (Opcode::IncrementDP, 2, 1));
+        // The pair is split over two lines
+        assert_eq!(op_at(ins[3]), (Opcode::DecrementByte, 2, 12));
+        assert_eq!(op_at(ins[4]), (Opcode::JumpBack, 3, 6));
+    }
+
+    #[test]
+    fn ook_error_position() {
+        let input = "Ook. Ook.\n  Ook! Ook?";
+        let p = Program::with_dialect("test.ook".to_string(), input, &Dialect::ook()).unwrap();
+        let error = p.validate().err().unwrap();
+        assert_eq!(error.to_string(), "Extra open bracket at line 2 column 3.");
+    }
+
+    #[test]
+    fn blub_and_comments() {
+        let input = "+ Blub. Blub. Blub! Blub.";
+        let p = Program::with_dialect("test.blub".to_string(), input, &Dialect::blub()).unwrap();
+        assert_eq!(p.to_string(), "+.");
+        // The + is only a comment in Blub
+        assert_eq!(op_at(&p.instructions()[0]), (Opcode::Comment('+'), 1, 1));
+    }
+
+    #[test]
+    fn brainfuck_dialect() {
+        let input = ">\n<+-.,\r\n[]";
+        let lexed = Dialect::brainfuck().lex(input);
+        assert_eq!(op_at(&lexed[1]), (Opcode::DecrementDP, 2, 1));
+        assert_eq!(op_at(&lexed[7]), (Opcode::JumpBack, 3, 2));
+        assert_eq!(lexed.len(), 8);
+    }
+
+    #[test]
+    fn custom_dialect() {
+        let config = r#"
+            name = "moo"
+
+            [tokens]
+            "moo" = "+"
+            "mooo" = "."
+        "#;
+        let dialect = Dialect::from_toml("cow", config).unwrap();
+        assert_eq!(dialect.name(), "moo");
+        let p = Program::with_dialect("test.moo".to_string(), "moo moo mooo", &dialect).unwrap();
+        assert_eq!(p.to_string(), "++.");
+        assert_eq!(op_at(&p.instructions()[4]), (Opcode::Output, 1, 9));
+    }
+
+    #[test]
+    fn convert_between_dialects() {
+        let input = "+[>-]\n\n.";
+        let p = Program::new("test.bf".to_string(), input);
+        let ook = Dialect::ook().emit(&p, false).unwrap();
+        assert_eq!(
+            ook,
+            "Ook. Ook. Ook! Ook? Ook. Ook? Ook! Ook! Ook? Ook!\n\nOok! Ook."
+        );
+
+        let back = Program::with_dialect("test.ook".to_string(), &ook, &Dialect::ook()).unwrap();
+        assert_eq!(Dialect::brainfuck().emit(&back, false).unwrap(), input);
+    }
+
+    #[test]
+    fn convert_keeps_comments() {
+        let input = "Ook. Ook. add one, then print\nOok! Ook.";
+        let p = Program::with_dialect("test.ook".to_string(), input, &Dialect::ook()).unwrap();
+        // The , in the comment would be an instruction in Brainfuck
+        assert_eq!(
+            Dialect::brainfuck().emit(&p, true).unwrap(),
+            "+ add one then print\n."
+        );
+        assert_eq!(Dialect::brainfuck().emit(&p, false).unwrap(), "+\n.");
+    }
+
+    #[test]
+    fn convert_included_files() {
+        // Line 2 of main.bf includes lib.bf
+        let mut sources = SourceMap::new();
+        sources.add("main.bf".to_string(), "+\n#include \"lib.bf\"\n.");
+        sources.add("lib.bf".to_string(), ">\n\n@");
+        let at = |line, file_id| Span::new(line, 1, 0).in_file(file_id);
+        let ins = vec![
+            Instruction::new(Opcode::IncrementByte, at(1, 0)),
+            Instruction::new(Opcode::IncrementDP, at(1, 1)),
+            Instruction::new(Opcode::EndProgram, at(3, 1)),
+            Instruction::new(Opcode::Output, at(3, 0)),
+        ];
+        let p = Program::from_instructions(sources, ins);
+        let dialect = Dialect::brainfuck().with_extension(Extension::Ebf1);
+        assert_eq!(dialect.emit(&p, false).unwrap(), "+>@\n\n.");
+        let error = Dialect::brainfuck().emit(&p, false).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Dialect bf has no token for @ at line 3 column 1 of lib.bf."
+        );
+    }
+
+    #[test]
+    fn extensions_are_opt_in() {
+        let input = "+@$!}{~^&|";
+        let p = Program::new("test.bf".to_string(), input);
+        assert_eq!(p.to_string(), "+");
+        assert_eq!(op_at(&p.instructions()[1]), (Opcode::Comment('@'), 1, 2));
+
+        let dialect = Dialect::brainfuck().with_extension(Extension::Ebf1);
+        let p = Program::with_dialect("test.bf".to_string(), input, &dialect).unwrap();
+        assert_eq!(p.to_string(), input);
+        assert_eq!(op_at(&p.instructions()[1]), (Opcode::EndProgram, 1, 2));
+        assert_eq!(op_at(&p.instructions()[9]), (Opcode::BitOr, 1, 10));
+
+        // Plain Brainfuck has no token for them
+        let error = Dialect::brainfuck().emit(&p, false).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Dialect bf has no token for @ at line 1 column 2."
+        );
+    }
+
+    #[test]
+    fn input_after_separator() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Separator);
+        let p = Program::with_dialect("test.bf".to_string(), ",.,.!hi!", &dialect).unwrap();
+        assert_eq!(p.to_string(), ",.,.");
+        assert_eq!(p.input(), Some("hi!"));
+        assert_eq!(dialect.emit(&p, false).unwrap(), ",.,.!hi!");
+        let error = Dialect::brainfuck().emit(&p, false).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Dialect bf has no separator for the input of the program."
+        );
+
+        // Without the extension it is a comment
+        let p = Program::new("test.bf".to_string(), ",.,.!hi!");
+        assert_eq!(p.input(), None);
+
+        // The ! inside the Ook! tokens is not a separator
+        let dialect = Dialect::ook().with_extension(Extension::Separator);
+        let p = Program::with_dialect("test.ook".to_string(), "Ook. Ook! ! x", &dialect).unwrap();
+        assert_eq!(p.to_string(), ",");
+        assert_eq!(p.input(), Some(" x"));
+    }
+
+    #[test]
+    fn debug_instruction() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Debug);
+        let p = Program::with_dialect("test.bf".to_string(), "+#", &dialect).unwrap();
+        assert_eq!(op_at(&p.instructions()[1]), (Opcode::DebugDump, 1, 2));
+        let p = Program::new("test.bf".to_string(), "+#");
+        assert_eq!(op_at(&p.instructions()[1]), (Opcode::Comment('#'), 1, 2));
+    }
+
+    #[test]
+    fn grid_wins_over_ebf1() {
+        let dialect = Dialect::brainfuck()
+            .with_extension(Extension::Ebf1)
+            .with_extension(Extension::Grid);
+        let p = Program::with_dialect("test.bf".to_string(), "^v~", &dialect).unwrap();
+        let ops: Vec<Opcode> = p.instructions().iter().map(|ins| ins.op).collect();
+        assert_eq!(ops, [Opcode::MoveUp, Opcode::MoveDown, Opcode::BitNot]);
+        let p = Program::new("test.bf".to_string(), "^v");
+        assert_eq!(op_at(&p.instructions()[1]), (Opcode::Comment('v'), 1, 2));
+    }
+
+    #[test]
+    fn custom_dialect_errors() {
+        let error = Dialect::from_toml("cow", "[tokens]\nmoo = \"x\"")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Token moo in dialect cow maps to x, which is not an instruction."
+        );
+        let error = Dialect::from_toml("cow", "[tokens]\nmoo = \"++\"")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Token moo in dialect cow must map to a single character, not ++."
+        );
+        // Extension characters need their extension
+        let error = Dialect::from_toml("cow", "[tokens]\nmoo = \"#\"")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Token moo in dialect cow maps to #, which is not an instruction."
+        );
+        let config = "extensions = [\"debug\"]\n[tokens]\nmoo = \"#\"";
+        let dialect = Dialect::from_toml("cow", config).unwrap();
+        assert!(dialect.has_extension(Extension::Debug));
+        let p = Program::with_dialect("test.moo".to_string(), "moo", &dialect).unwrap();
+        assert_eq!(p.instructions()[0].op, Opcode::DebugDump);
+    }
+}
@@ -0,0 +1,530 @@
+//! Recognises the common idioms of Brainfuck code, to explain what a program
+//! does.
+//!
+//! The explanation is a list of [`Annotation`]s in the order of the code. The
+//! pseudo code names the cells by their distance from the head where the
+//! annotated code starts: `p[0]` is the cell under the head and `p[1]` the one
+//! on its right.
+
+use crate::{Instruction, Opcode, Span};
+use std::collections::HashMap;
+use std::fmt;
+
+/// The kind of code an [`Annotation`] explains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idiom {
+    /// Code without loops, changing cells and moving the head.
+    Code,
+    /// Code without loops or input, which prints known bytes. The summary
+    /// only shows the bytes.
+    Print,
+    /// `[-]`: sets the cell to zero.
+    Clear,
+    /// `[->+<]`: adds the cell to another one, and clears it.
+    Move,
+    /// `[->+>+<<]`: adds the cell to several others, and clears it.
+    Copy,
+    /// `[->+++<]`: adds multiples of the cell to others, and clears it.
+    Multiply,
+    /// `[>]`: moves the head to the next zero cell.
+    Scan,
+    /// Any other loop, the annotations of its body follow it.
+    Loop,
+}
+
+impl fmt::Display for Idiom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Idiom::Code => "code",
+            Idiom::Print => "print",
+            Idiom::Clear => "clear",
+            Idiom::Move => "move",
+            Idiom::Copy => "copy",
+            Idiom::Multiply => "multiply",
+            Idiom::Scan => "scan",
+            Idiom::Loop => "loop",
+        };
+        f.pad(name)
+    }
+}
+
+/// Explains a run of instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    /// What kind of code it is.
+    pub idiom: Idiom,
+    /// Span of the first instruction.
+    pub start: Span,
+    /// Span of the last instruction.
+    pub end: Span,
+    /// Number of loops around the code.
+    pub depth: usize,
+    /// What the code does, in pseudo code.
+    pub summary: String,
+}
+
+/// Explains the instructions, which must have matching brackets.
+pub(crate) fn explain(ins: &[Instruction]) -> Vec<Annotation> {
+    let mut explainer = Explainer {
+        ins: ins
+            .iter()
+            .filter(|ins| !matches!(ins.op, Opcode::Comment(_)))
+            .copied()
+            .collect(),
+        pos: 0,
+        annotations: Vec::new(),
+    };
+    explainer.block(0, &mut Knowledge::zero());
+    explainer.annotations
+}
+
+/// What is known of the values of the cells.
+///
+/// The offsets are from where the head was when the knowledge started.
+struct Knowledge {
+    head: isize,
+    cells: HashMap<isize, Option<u8>>,
+    /// Value of the cells missing from `cells`.
+    rest: Option<u8>,
+}
+
+impl Knowledge {
+    /// The tape at the start of the program, all zero.
+    fn zero() -> Self {
+        Knowledge {
+            head: 0,
+            cells: HashMap::new(),
+            rest: Some(0),
+        }
+    }
+
+    /// Nothing is known but the cell under the head.
+    fn only(value: Option<u8>) -> Self {
+        let mut knowledge = Knowledge {
+            head: 0,
+            cells: HashMap::new(),
+            rest: None,
+        };
+        knowledge.set(0, value);
+        knowledge
+    }
+
+    /// Returns the value of the cell at `offset` from the head, if known.
+    fn get(&self, offset: isize) -> Option<u8> {
+        *self.cells.get(&(self.head + offset)).unwrap_or(&self.rest)
+    }
+
+    fn set(&mut self, offset: isize, value: Option<u8>) {
+        self.cells.insert(self.head + offset, value);
+    }
+}
+
+struct Explainer {
+    ins: Vec<Instruction>,
+    pos: usize,
+    annotations: Vec<Annotation>,
+}
+
+impl Explainer {
+    /// Explains the instructions up to the end of the current loop.
+    fn block(&mut self, depth: usize, known: &mut Knowledge) {
+        while let Some(ins) = self.ins.get(self.pos) {
+            match ins.op {
+                Opcode::JumpBack => return,
+                Opcode::JumpForward => self.loop_at(depth, known),
+                _ => self.straight(depth, known),
+            }
+        }
+    }
+
+    /// Explains the code up to the next bracket.
+    fn straight(&mut self, depth: usize, known: &mut Knowledge) {
+        let start = self.pos;
+        let mut stmts = Stmts::default();
+        // The known bytes printed, unless something else is printed or read
+        let mut text = Some(Vec::new());
+        let mut offset = 0;
+
+        while let Some(ins) = self.ins.get(self.pos) {
+            match ins.op {
+                Opcode::JumpForward | Opcode::JumpBack => break,
+                Opcode::IncrementDP => {
+                    offset += 1;
+                    known.head += 1;
+                }
+                Opcode::DecrementDP => {
+                    offset -= 1;
+                    known.head -= 1;
+                }
+                Opcode::IncrementByte | Opcode::DecrementByte => {
+                    let delta = if ins.op == Opcode::IncrementByte {
+                        1
+                    } else {
+                        255
+                    };
+                    stmts.add(offset, delta);
+                    known.set(0, known.get(0).map(|value| value.wrapping_add(delta)));
+                }
+                Opcode::Output => match known.get(0) {
+                    Some(byte) => {
+                        stmts.print_byte(byte);
+                        if let Some(text) = text.as_mut() {
+                            text.push(byte);
+                        }
+                    }
+                    None => {
+                        stmts.push(format!("print p[{}]", offset));
+                        text = None;
+                    }
+                },
+                Opcode::Input => {
+                    stmts.push(format!("p[{}] = input", offset));
+                    known.set(0, None);
+                    text = None;
+                }
+                op => {
+                    let ch = op.as_char().unwrap_or(' ');
+                    stmts.push(format!("'{}' at p[{}]", ch, offset));
+                    *known = Knowledge::only(None);
+                    text = None;
+                }
+            }
+            self.pos += 1;
+        }
+
+        let (idiom, summary) = match text {
+            Some(text) if !text.is_empty() => {
+                (Idiom::Print, format!("print \"{}\"", escape(&text)))
+            }
+            _ => (Idiom::Code, stmts.finish(offset)),
+        };
+        self.annotate(idiom, start, self.pos - 1, depth, summary);
+    }
+
+    /// Explains the loop starting at the current instruction.
+    fn loop_at(&mut self, depth: usize, known: &mut Knowledge) {
+        let start = self.pos;
+        let end = self.matching(start);
+        let body = &self.ins[start + 1..end];
+
+        if let Some((idiom, summary)) = simple_loop(body, known) {
+            self.annotate(idiom, start, end, depth, summary);
+            self.pos = end + 1;
+            return;
+        }
+
+        let scan = scan_step(body);
+        if let Some(step) = scan {
+            let summary = format!("while p[0] != 0 {{ head {} }}", shift(step));
+            self.annotate(Idiom::Scan, start, end, depth, summary);
+        } else {
+            let summary = "while p[0] != 0".to_string();
+            self.annotate(Idiom::Loop, start, end, depth, summary);
+            self.pos = start + 1;
+            self.block(depth + 1, &mut Knowledge::only(None));
+        }
+        // The loop only ends on a zero cell
+        *known = Knowledge::only(Some(0));
+        self.pos = end + 1;
+    }
+
+    /// Returns the index of the bracket closing the loop opened at `open`.
+    fn matching(&self, open: usize) -> usize {
+        let mut level = 0;
+        for (pos, ins) in self.ins.iter().enumerate().skip(open) {
+            match ins.op {
+                Opcode::JumpForward => level += 1,
+                Opcode::JumpBack => {
+                    level -= 1;
+                    if level == 0 {
+                        return pos;
+                    }
+                }
+                _ => (),
+            }
+        }
+        self.ins.len() - 1
+    }
+
+    fn annotate(&mut self, idiom: Idiom, start: usize, end: usize, depth: usize, summary: String) {
+        self.annotations.push(Annotation {
+            idiom,
+            start: self.ins[start].span,
+            end: self.ins[end].span,
+            depth,
+            summary,
+        });
+    }
+}
+
+/// Recognises a loop which only adds to cells and comes back to the same
+/// cell, decrementing it: clear, move, copy and multiply.
+///
+/// Updates what is known of the cells it changes.
+fn simple_loop(body: &[Instruction], known: &mut Knowledge) -> Option<(Idiom, String)> {
+    let mut offset = 0;
+    // The changes of the cells, in the order they are first changed
+    let mut changes: Vec<(isize, u8)> = Vec::new();
+    for ins in body {
+        let delta = match ins.op {
+            Opcode::IncrementDP => {
+                offset += 1;
+                continue;
+            }
+            Opcode::DecrementDP => {
+                offset -= 1;
+                continue;
+            }
+            Opcode::IncrementByte => 1,
+            Opcode::DecrementByte => 255,
+            _ => return None,
+        };
+        match changes.iter_mut().find(|(at, _)| *at == offset) {
+            Some((_, change)) => *change = change.wrapping_add(delta),
+            None => changes.push((offset, delta)),
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+
+    let counter = changes
+        .iter()
+        .find(|(at, _)| *at == 0)
+        .map_or(0, |(_, change)| *change);
+    let targets: Vec<(isize, u8)> = changes
+        .into_iter()
+        .filter(|(at, change)| *at != 0 && *change != 0)
+        .collect();
+    let idiom = match (counter, targets.len()) {
+        (1, 0) | (255, 0) => Idiom::Clear,
+        (255, 1) if targets[0].1 == 1 => Idiom::Move,
+        (255, _) if targets.iter().all(|(_, factor)| *factor == 1) => Idiom::Copy,
+        (255, _) => Idiom::Multiply,
+        _ => return None,
+    };
+
+    let mut summary: Vec<String> = Vec::new();
+    let count = known.get(0);
+    for (at, factor) in targets {
+        let (sign, factor) = if factor > 128 {
+            ('-', 0u8.wrapping_sub(factor))
+        } else {
+            ('+', factor)
+        };
+        if factor == 1 {
+            summary.push(format!("p[{}] {}= p[0]", at, sign));
+        } else {
+            summary.push(format!("p[{}] {}= {} * p[0]", at, sign, factor));
+        }
+        let value = match (count, known.get(at)) {
+            (Some(count), Some(value)) => {
+                let product = count.wrapping_mul(factor);
+                Some(if sign == '-' {
+                    value.wrapping_sub(product)
+                } else {
+                    value.wrapping_add(product)
+                })
+            }
+            _ => None,
+        };
+        known.set(at, value);
+    }
+    summary.push("p[0] = 0".to_string());
+    known.set(0, Some(0));
+    Some((idiom, summary.join("; ")))
+}
+
+/// Returns the net head movement of a loop body which only moves the head.
+fn scan_step(body: &[Instruction]) -> Option<isize> {
+    let mut step = 0;
+    for ins in body {
+        match ins.op {
+            Opcode::IncrementDP => step += 1,
+            Opcode::DecrementDP => step -= 1,
+            _ => return None,
+        }
+    }
+    if step != 0 {
+        Some(step)
+    } else {
+        None
+    }
+}
+
+/// The pseudo code of straight code, merging the changes to the cells until
+/// something is printed or read.
+#[derive(Default)]
+struct Stmts {
+    done: Vec<String>,
+    changes: Vec<(isize, u8)>,
+    text: Vec<u8>,
+}
+
+impl Stmts {
+    fn add(&mut self, offset: isize, delta: u8) {
+        self.flush_text();
+        match self.changes.iter_mut().find(|(at, _)| *at == offset) {
+            Some((_, change)) => *change = change.wrapping_add(delta),
+            None => self.changes.push((offset, delta)),
+        }
+    }
+
+    fn print_byte(&mut self, byte: u8) {
+        self.flush_changes();
+        self.text.push(byte);
+    }
+
+    fn push(&mut self, stmt: String) {
+        self.flush_changes();
+        self.flush_text();
+        self.done.push(stmt);
+    }
+
+    fn flush_changes(&mut self) {
+        for (at, change) in self.changes.drain(..) {
+            if change > 128 {
+                self.done
+                    .push(format!("p[{}] -= {}", at, 0u8.wrapping_sub(change)));
+            } else if change != 0 {
+                self.done.push(format!("p[{}] += {}", at, change));
+            }
+        }
+    }
+
+    fn flush_text(&mut self) {
+        if !self.text.is_empty() {
+            self.done.push(format!("print \"{}\"", escape(&self.text)));
+            self.text.clear();
+        }
+    }
+
+    /// Returns the pseudo code, ending with the head moved by `offset`.
+    fn finish(mut self, offset: isize) -> String {
+        self.flush_changes();
+        self.flush_text();
+        if offset != 0 {
+            self.done.push(format!("head {}", shift(offset)));
+        }
+        if self.done.is_empty() {
+            "nothing".to_string()
+        } else {
+            self.done.join("; ")
+        }
+    }
+}
+
+/// Pseudo code moving the head by `offset`.
+fn shift(offset: isize) -> String {
+    if offset < 0 {
+        format!("-= {}", -offset)
+    } else {
+        format!("+= {}", offset)
+    }
+}
+
+/// Escapes the bytes to show them in a string literal.
+fn escape(text: &[u8]) -> String {
+    text.iter()
+        .flat_map(|byte| std::ascii::escape_default(*byte))
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::explain::Idiom;
+    use crate::Program;
+
+    /// Explains the code and returns the idiom, depth and summary of each
+    /// annotation.
+    fn explain(code: &str) -> Vec<(Idiom, usize, String)> {
+        Program::new("test.bf".to_string(), code)
+            .explain()
+            .unwrap()
+            .into_iter()
+            .map(|a| (a.idiom, a.depth, a.summary))
+            .collect()
+    }
+
+    #[test]
+    fn loop_idioms() {
+        let code = ",[-]>,[->+<]>,[->+>+<<]>,[->+++>--<<]<<[<]";
+        let summaries: Vec<(Idiom, String)> = explain(code)
+            .into_iter()
+            .map(|(idiom, _, summary)| (idiom, summary))
+            .collect();
+        assert_eq!(
+            summaries,
+            vec![
+                (Idiom::Code, "p[0] = input".to_string()),
+                (Idiom::Clear, "p[0] = 0".to_string()),
+                (Idiom::Code, "p[1] = input; head += 1".to_string()),
+                (Idiom::Move, "p[1] += p[0]; p[0] = 0".to_string()),
+                (Idiom::Code, "p[1] = input; head += 1".to_string()),
+                (
+                    Idiom::Copy,
+                    "p[1] += p[0]; p[2] += p[0]; p[0] = 0".to_string()
+                ),
+                (Idiom::Code, "p[1] = input; head += 1".to_string()),
+                (
+                    Idiom::Multiply,
+                    "p[1] += 3 * p[0]; p[2] -= 2 * p[0]; p[0] = 0".to_string()
+                ),
+                (Idiom::Code, "head -= 2".to_string()),
+                (Idiom::Scan, "while p[0] != 0 { head -= 1 }".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn print_constant() {
+        // Known values flow through the multiplication loop
+        let hi = "++++++++[>+++++++++<-]>.+.[-]++++++++++.";
+        assert_eq!(
+            explain(hi),
+            vec![
+                (Idiom::Code, 0, "p[0] += 8".to_string()),
+                (Idiom::Multiply, 0, "p[1] += 9 * p[0]; p[0] = 0".to_string()),
+                (Idiom::Print, 0, "print \"HI\"".to_string()),
+                (Idiom::Clear, 0, "p[0] = 0".to_string()),
+                (Idiom::Print, 0, "print \"\\n\"".to_string()),
+            ]
+        );
+        // The value read is not known any more
+        assert_eq!(
+            explain(",.++."),
+            vec![(
+                Idiom::Code,
+                0,
+                "p[0] = input; print p[0]; p[0] += 2; print p[0]".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn nested_loops() {
+        let code = "+[>,.[-]<]\n>.";
+        let annotations = Program::new("test.bf".to_string(), code).explain().unwrap();
+        let depths: Vec<(Idiom, usize)> = annotations.iter().map(|a| (a.idiom, a.depth)).collect();
+        assert_eq!(
+            depths,
+            vec![
+                (Idiom::Code, 0),
+                (Idiom::Loop, 0),
+                (Idiom::Code, 1),
+                (Idiom::Clear, 1),
+                (Idiom::Code, 1),
+                (Idiom::Code, 0),
+            ]
+        );
+        let outer = &annotations[1];
+        assert_eq!((outer.start.line, outer.start.col), (1, 2));
+        assert_eq!((outer.end.line, outer.end.col), (1, 10));
+        // Only the cell the loop stopped on is known afterwards
+        assert_eq!(annotations[5].summary, "print p[1]; head += 1");
+        assert!(Program::new("test.bf".to_string(), "[[]")
+            .explain()
+            .is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! The `#include "file"` directive, to build a program from several files.
+//!
+//! A line holding only `#include "lib/print.bf"` is replaced by the program in
+//! that file, before the lexer sees the line. The path is relative to the
+//! directory of the including file. Any other line is left to the lexer, so a
+//! comment like `#includes go here` stays a comment, and so do the lines after
+//! the input separator. A `!` in the path of a directive is not the
+//! separator. Every file keeps its own spans in the [`SourceMap`],
+//! so errors point into the file they come from.
+
+use crate::macros::{is_definition, Macros};
+use crate::{Dialect, Extension, Instruction, SourceMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The text starting a directive line.
+const DIRECTIVE: &str = "#include";
+
+/// Reads the files of a program, and follows their includes.
+#[derive(Debug, Default)]
+pub(crate) struct Includer {
+    /// All the files read so far.
+    pub(crate) sources: SourceMap,
+    /// The canonical paths of the files being included, to find cycles.
+    stack: Vec<PathBuf>,
+    /// The macros of all the files read so far.
+    macros: Macros,
+}
+
+impl Includer {
+    /// Lexes the file at `path` with its includes expanded, and returns the
+    /// instructions along with the input after the separator, if any.
+    ///
+    /// The input is only taken from the first file, the ones it includes can
+    /// only add instructions.
+    pub(crate) fn lex_file(
+        &mut self,
+        path: &Path,
+        dialect: &Dialect,
+    ) -> io::Result<(Vec<Instruction>, Option<String>)> {
+        let canonical = fs::canonicalize(path)?;
+        if self.stack.contains(&canonical) {
+            let mut cycle: Vec<String> = self
+                .stack
+                .iter()
+                .skip_while(|file| **file != canonical)
+                .map(|file| file.display().to_string())
+                .collect();
+            cycle.push(canonical.display().to_string());
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Include cycle: {}.", cycle.join(" -> ")),
+            ));
+        }
+        let content = fs::read_to_string(path)?;
+        let filename = match path.to_str() {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Other, "Filename not unicode"));
+            }
+        };
+
+        // The text after the separator is input, not directives
+        let macros = dialect.has_extension(Extension::Macros);
+        let program_end = dialect
+            .split_input(&content, |line| {
+                parse_directive(line).is_some() || (macros && is_definition(line))
+            })
+            .0
+            .len();
+
+        // Blank the directives, so the lexer skips them without moving the
+        // other lines
+        let mut includes = Vec::new();
+        let mut blanked = String::with_capacity(content.len());
+        for (index, line) in content.split_inclusive('\n').enumerate() {
+            let directive = if blanked.len() + line.len() <= program_end {
+                parse_directive(line)
+            } else {
+                None
+            };
+            match directive {
+                Some(target) => {
+                    includes.push((index + 1, target));
+                    blanked.push_str(&" ".repeat(line.trim_end().len()));
+                    blanked.push_str(&line[line.trim_end().len()..]);
+                }
+                None => blanked.push_str(line),
+            }
+        }
+
+        let file_id = self.sources.add(filename.clone(), &content);
+
+        // The included files first, so their macros are known here
+        self.stack.push(canonical);
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut included = Vec::with_capacity(includes.len());
+        for (line, target) in includes {
+            let (ins, _) = self.lex_file(&dir.join(&target), dialect).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Can not include {} at {}:{}: {}", target, filename, line, e),
+                )
+            })?;
+            included.push((line, ins));
+        }
+        self.stack.pop();
+
+        let (mut ins, input) = if dialect.has_extension(Extension::Macros) {
+            self.macros.lex(dialect, file_id, &filename, &blanked)?
+        } else {
+            dialect.lex_with_input(file_id, &blanked)
+        };
+        // Splice from the last include, so the earlier indices stay valid
+        for (line, included) in included.into_iter().rev() {
+            let at = ins
+                .iter()
+                .position(|ins| ins.span.line > line)
+                .unwrap_or(ins.len());
+            ins.splice(at..at, included);
+        }
+        Ok((ins, input))
+    }
+}
+
+/// Reads the include directive on the line, if the line is one, and returns
+/// the quoted path.
+fn parse_directive(line: &str) -> Option<String> {
+    let rest = line.trim().strip_prefix(DIRECTIVE)?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    rest.trim()
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .filter(|path| !path.is_empty() && !path.contains('"'))
+        .map(|path| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::include::parse_directive;
+    use crate::{Dialect, Extension, Opcode, Program};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Writes the `(path, source)` files of a program in a new directory
+    /// named after the test, and returns the directory.
+    fn write_program(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("bft-include-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (path, source) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn directives() {
+        assert_eq!(
+            parse_directive("  #include \"lib/print.bf\"\n"),
+            Some("lib/print.bf".to_string())
+        );
+        // Anything else is a comment
+        assert_eq!(parse_directive("#include lib.bf"), None);
+        assert_eq!(parse_directive("#includes go here"), None);
+        assert_eq!(parse_directive("#includes \"a\""), None);
+        assert_eq!(parse_directive("+ #include \"a\""), None);
+    }
+
+    #[test]
+    fn nested_includes() {
+        let root = write_program(
+            "nested",
+            &[
+                ("main.bf", "+\n#include \"lib/a.bf\"\n-\n"),
+                // Relative to lib/a.bf
+                ("lib/a.bf", ">\n  #include \"b.bf\"\n<"),
+                ("lib/b.bf", "\n.]"),
+            ],
+        );
+
+        let p = Program::from_file(root.join("main.bf")).unwrap();
+        assert_eq!(p.to_string(), "+>.]<-");
+        assert_eq!(p.source_map().len(), 3);
+
+        let close = p
+            .instructions()
+            .iter()
+            .find(|ins| ins.op == Opcode::JumpBack);
+        let span = close.unwrap().span;
+        assert!(p.source_map().name(span.file_id).unwrap().ends_with("b.bf"));
+        assert_eq!((span.line, span.col, span.byte_offset), (2, 2, 2));
+        let minus = p.instructions().last().unwrap();
+        assert_eq!(minus.span.file_id, 0);
+        assert_eq!(minus.position(), (3, 1));
+
+        let error = p.validate().err().unwrap().to_string();
+        assert!(error.starts_with("Extra close bracket at line 2 column 2 of "));
+        assert!(error.ends_with("b.bf."));
+    }
+
+    #[test]
+    fn include_cycle() {
+        let root = write_program(
+            "cycle",
+            &[
+                ("a.bf", "#include \"b.bf\"\n"),
+                ("b.bf", "+\n#include \"a.bf\"\n"),
+                ("c.bf", "#include \"d.bf\"\n#include \"d.bf\""),
+                ("d.bf", "+"),
+            ],
+        );
+
+        let error = Program::from_file(root.join("a.bf")).err().unwrap();
+        let message = error.to_string();
+        assert!(message.contains("Include cycle: "), "{}", message);
+        assert!(message.contains("a.bf -> "), "{}", message);
+
+        // Including the same file twice is fine
+        let dialect = Dialect::brainfuck().with_extension(Extension::Debug);
+        let p = Program::from_file_with_dialect(root.join("c.bf"), &dialect).unwrap();
+        assert_eq!(p.to_string(), "++");
+    }
+
+    #[test]
+    fn missing_include() {
+        let root = write_program("missing", &[("a.bf", "+\n#include \"nope.bf\"\n")]);
+        let error = Program::from_file(root.join("a.bf")).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        assert!(error.to_string().starts_with("Can not include nope.bf at "));
+    }
+
+    #[test]
+    fn comments_and_input_are_not_included() {
+        let root = write_program(
+            "input",
+            &[
+                (
+                    "main.bf",
+                    "#includes go here\n#include \"lib.bf\"\n,.!\n#include \"lib.bf\"\n",
+                ),
+                ("lib.bf", "+"),
+            ],
+        );
+        let dialect = Dialect::brainfuck().with_extension(Extension::Separator);
+        let p = Program::from_file_with_dialect(root.join("main.bf"), &dialect).unwrap();
+        assert_eq!(p.to_string(), "+,.");
+        assert_eq!(p.input(), Some("\n#include \"lib.bf\"\n"));
+    }
+
+    #[test]
+    fn separator_in_the_path() {
+        let root = write_program(
+            "separator",
+            &[("main.bf", "#include \"a!b.bf\"\n.!x"), ("a!b.bf", "+")],
+        );
+        let dialect = Dialect::brainfuck().with_extension(Extension::Separator);
+        let p = Program::from_file_with_dialect(root.join("main.bf"), &dialect).unwrap();
+        assert_eq!(p.to_string(), "+.");
+        assert_eq!(p.input(), Some("x"));
+    }
+
+    #[test]
+    fn separator_in_a_macro() {
+        let root = write_program(
+            "bang",
+            &[
+                ("main.bf", "#define bang !\n#include \"lib.bf\"\nbang!x"),
+                ("lib.bf", "+"),
+            ],
+        );
+        let dialect = Dialect::brainfuck()
+            .with_extension(Extension::Macros)
+            .with_extension(Extension::Separator);
+        let p = Program::from_file_with_dialect(root.join("main.bf"), &dialect).unwrap();
+        assert_eq!(p.to_string(), "+");
+        assert_eq!(p.input(), Some("x"));
+    }
+
+    #[test]
+    fn included_macros() {
+        let root = write_program(
+            "macros",
+            &[
+                ("lib.bf", "#define clear [-]\n#const A 65\n"),
+                ("main.bf", "#include \"lib.bf\"\n+*A.clear"),
+            ],
+        );
+        let dialect = Dialect::brainfuck().with_extension(Extension::Macros);
+        let p = Program::from_file_with_dialect(root.join("main.bf"), &dialect).unwrap();
+        assert_eq!(p.to_string(), format!("{}.[-]", "+".repeat(65)));
+        assert_eq!(p.instructions().last().unwrap().position(), (2, 5));
+    }
+}
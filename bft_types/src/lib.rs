@@ -1,87 +1,214 @@
 //! Instructions for the brainfuck interpreter.
 
-use std::convert::TryFrom;
+// The errors are built with `io::Error::new(io::ErrorKind::Other, ..)`
+#![allow(clippy::io_other_error)]
+
+use include::Includer;
+use macros::Macros;
 use std::fmt;
-use std::fs;
 use std::io;
 use std::path::Path;
 
-type SourceInput = (usize, usize, char);
+mod analysis;
+mod dialect;
+mod explain;
+mod include;
+mod macros;
+mod span;
+pub use analysis::{Analysis, LoopReport};
+pub use dialect::{Dialect, Extension};
+pub use explain::{Annotation, Idiom};
+pub use span::{SourceMap, Span};
 
-/// Instruction enum represents each instruction from our code.
+/// The operation of an instruction, without its position in the source.
 ///
 /// This even stores any code comments as `char`. The language has
-/// 8 single byte long characters as commands/instructions. Every value
-/// also stores the line number, and the column number on the line.
-#[allow(dead_code)]
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub enum Instruction {
+/// 8 single byte long characters as commands/instructions, the others
+/// come from the extensions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Opcode {
     /// Increment the data pointer (to point to the next cell to the right).
-    IncrementDP(usize, usize),
+    IncrementDP,
     /// Decrement the data pointer (to point to the next cell to the left).
-    DecrementDP(usize, usize),
+    DecrementDP,
     /// Increment (increase by one) the byte at the data pointer.
-    IncrementByte(usize, usize),
+    IncrementByte,
     /// Decrement (decrease by one) the byte at the data pointer.
-    DecrementByte(usize, usize),
+    DecrementByte,
     /// Output the byte at the data pointer.
-    Output(usize, usize),
+    Output,
     /// Accept one byte of input, storing its value in the byte at the data pointer.
-    Input(usize, usize),
+    Input,
     /// If the byte at the data pointer is zero, then instead of moving the instruction
     /// pointer forward to the next command, jump it forward to the command after the matching `]` command.
-    JumpForward(usize, usize),
+    JumpForward,
     /// If the byte at the data pointer is nonzero, then instead of moving the instruction
     /// pointer forward to the next command, jump it back to the command after the matching `[` command.
-    JumpBack(usize, usize),
+    JumpBack,
     /// Any other character on the source code.
-    Comment(usize, usize, char),
+    Comment(char),
+    /// Extended Type I `@`: end the program.
+    EndProgram,
+    /// Extended Type I `$`: overwrite the storage with the byte at the data pointer.
+    SaveStorage,
+    /// Extended Type I `!`: overwrite the byte at the data pointer with the storage.
+    LoadStorage,
+    /// Extended Type I `}`: shift the byte at the data pointer one bit to the right.
+    ShiftRight,
+    /// Extended Type I `{`: shift the byte at the data pointer one bit to the left.
+    ShiftLeft,
+    /// Extended Type I `~`: bitwise NOT of the byte at the data pointer.
+    BitNot,
+    /// Extended Type I `^`: bitwise XOR of the byte at the data pointer and the storage.
+    BitXor,
+    /// Extended Type I `&`: bitwise AND of the byte at the data pointer and the storage.
+    BitAnd,
+    /// Extended Type I `|`: bitwise OR of the byte at the data pointer and the storage.
+    BitOr,
+    /// Debug `#`: print the state of the machine to stderr.
+    DebugDump,
+    /// pbrain `(`: define the procedure numbered by the byte at the data pointer,
+    /// up to the matching `)`.
+    ProcedureStart,
+    /// pbrain `)`: return from the procedure.
+    ProcedureEnd,
+    /// pbrain `:`: call the procedure numbered by the byte at the data pointer.
+    CallProcedure,
+    /// 2D `^`: move the data pointer one row up.
+    MoveUp,
+    /// 2D `v`: move the data pointer one row down.
+    MoveDown,
 }
 
-impl TryFrom<SourceInput> for Instruction {
-    type Error = Box<dyn std::error::Error>;
+impl Opcode {
+    /// Returns the Brainfuck character for this opcode, or `None` for comments.
+    pub fn as_char(&self) -> Option<char> {
+        match self {
+            Opcode::IncrementDP => Some('>'),
+            Opcode::DecrementDP => Some('<'),
+            Opcode::IncrementByte => Some('+'),
+            Opcode::DecrementByte => Some('-'),
+            Opcode::Output => Some('.'),
+            Opcode::Input => Some(','),
+            Opcode::JumpForward => Some('['),
+            Opcode::JumpBack => Some(']'),
+            Opcode::Comment(_) => None,
+            Opcode::EndProgram => Some('@'),
+            Opcode::SaveStorage => Some('$'),
+            Opcode::LoadStorage => Some('!'),
+            Opcode::ShiftRight => Some('}'),
+            Opcode::ShiftLeft => Some('{'),
+            Opcode::BitNot => Some('~'),
+            Opcode::BitXor => Some('^'),
+            Opcode::BitAnd => Some('&'),
+            Opcode::BitOr => Some('|'),
+            Opcode::DebugDump => Some('#'),
+            Opcode::ProcedureStart => Some('('),
+            Opcode::ProcedureEnd => Some(')'),
+            Opcode::CallProcedure => Some(':'),
+            Opcode::MoveUp => Some('^'),
+            Opcode::MoveDown => Some('v'),
+        }
+    }
+}
 
-    /// Converts a given `char` to the corresponding Brainfuck instruction.
+impl From<char> for Opcode {
+    /// Converts a given `char` to the corresponding Brainfuck opcode.
     ///
-    /// Other than the primary 8 chars, everything else is considered as comments.
-    fn try_from(source: SourceInput) -> Result<Self, Self::Error> {
-        match source {
-            (linenumber, charnumber, '>') => Ok(Instruction::IncrementDP(linenumber, charnumber)),
-            (linenumber, charnumber, '<') => Ok(Instruction::DecrementDP(linenumber, charnumber)),
-            (linenumber, charnumber, '+') => Ok(Instruction::IncrementByte(linenumber, charnumber)),
-            (linenumber, charnumber, '-') => Ok(Instruction::DecrementByte(linenumber, charnumber)),
-            (linenumber, charnumber, '.') => Ok(Instruction::Output(linenumber, charnumber)),
-            (linenumber, charnumber, ',') => Ok(Instruction::Input(linenumber, charnumber)),
-            (linenumber, charnumber, '[') => Ok(Instruction::JumpForward(linenumber, charnumber)),
-            (linenumber, charnumber, ']') => Ok(Instruction::JumpBack(linenumber, charnumber)),
-            (linenumber, charnumber, value) => {
-                Ok(Instruction::Comment(linenumber, charnumber, value))
-            }
+    /// Other than the primary 8 chars and the extension chars, everything
+    /// else is considered as comments. The [`Dialect`] decides which characters
+    /// are read as instructions, so plain Brainfuck never calls this for the
+    /// extension chars. The 2D `^` is never returned here, as it shares the
+    /// character with the Extended Type I `^`.
+    fn from(ch: char) -> Self {
+        match ch {
+            '>' => Opcode::IncrementDP,
+            '<' => Opcode::DecrementDP,
+            '+' => Opcode::IncrementByte,
+            '-' => Opcode::DecrementByte,
+            '.' => Opcode::Output,
+            ',' => Opcode::Input,
+            '[' => Opcode::JumpForward,
+            ']' => Opcode::JumpBack,
+            '@' => Opcode::EndProgram,
+            '$' => Opcode::SaveStorage,
+            '!' => Opcode::LoadStorage,
+            '}' => Opcode::ShiftRight,
+            '{' => Opcode::ShiftLeft,
+            '~' => Opcode::BitNot,
+            '^' => Opcode::BitXor,
+            '&' => Opcode::BitAnd,
+            '|' => Opcode::BitOr,
+            '#' => Opcode::DebugDump,
+            '(' => Opcode::ProcedureStart,
+            ')' => Opcode::ProcedureEnd,
+            ':' => Opcode::CallProcedure,
+            'v' => Opcode::MoveDown,
+            value => Opcode::Comment(value),
         }
     }
 }
 
-/// Stores the full program instruction set in a Vector and also the filename of the
-/// source code.
-#[allow(dead_code)]
-#[derive(Debug)]
+/// One instruction from our code, the opcode and where it was written.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Instruction {
+    /// What the instruction does.
+    pub op: Opcode,
+    /// Where the instruction is in the source.
+    pub span: Span,
+}
+
+impl Instruction {
+    /// Creates an instruction.
+    pub fn new(op: Opcode, span: Span) -> Self {
+        Instruction { op, span }
+    }
+
+    /// Returns the Brainfuck character for this instruction, or `None` for comments.
+    pub fn as_char(&self) -> Option<char> {
+        self.op.as_char()
+    }
+
+    /// Returns the line and column number of the instruction in the source code.
+    pub fn position(&self) -> (usize, usize) {
+        (self.span.line, self.span.col)
+    }
+}
+
+/// Stores the full program instruction set in a Vector and also the source files
+/// the instructions point into.
+#[derive(Debug, Clone)]
 pub struct Program {
-    filename: String,
+    sources: SourceMap,
     ins: Vec<Instruction>,
+    /// Input data written in the source after the `!` separator.
+    input: Option<String>,
 }
 
 impl Program {
     /// Takes a program path, and returns a `Program` structure holding all the instructions.
     /// We also strip the actual comments, and mark that in the instruction set.
     pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
-        let content = fs::read_to_string(&path)?;
-        let filename = match path.as_ref().to_str() {
-            Some(name) => name.to_string(),
-            None => {
-                return Err(io::Error::new(io::ErrorKind::Other, "Filename not unicode"));
-            }
-        };
-        Ok(Program::new(filename, &content))
+        Program::from_file_with_dialect(path, &Dialect::brainfuck())
+    }
+
+    /// Same as [`Program::from_file`], but the source is written in the given dialect.
+    ///
+    /// A line holding only `#include "file"` is replaced by the program in that
+    /// file, relative to the including file. Includes can nest, but not in a
+    /// cycle. With the [`Extension::Macros`] the macros are expanded, and the
+    /// macros of an included file can be used in the including file.
+    pub fn from_file_with_dialect<P: AsRef<Path>>(
+        path: P,
+        dialect: &Dialect,
+    ) -> std::io::Result<Self> {
+        let mut includer = Includer::default();
+        let (ins, input) = includer.lex_file(path.as_ref(), dialect)?;
+        Ok(Program {
+            sources: includer.sources,
+            ins,
+            input,
+        })
     }
 
     /// Creates a new instance of the Program structure
@@ -95,27 +222,89 @@ impl Program {
     /// let ins = Program::new("test.bf".to_string(), &code)
     /// ```
     pub fn new(filename: String, content: &str) -> Self {
-        let ins = content
-            .lines() // Get all the lines
-            .enumerate() // We want go through each line
-            .map(|(linenumber, line)| {
-                line.chars() // Now for each character in the line
-                    .enumerate()
-                    .map(|(charnumber, ch)| {
-                        let source: SourceInput = (linenumber + 1, charnumber + 1, ch); // Create a tuple with line number, column number, and the actual character.
-                        Instruction::try_from(source).ok().unwrap()
-                    })
-                    .collect::<Vec<Instruction>>()
-            })
-            .flatten()
-            .collect::<Vec<Instruction>>();
+        Program::lexed(filename, content, &Dialect::brainfuck())
+    }
+
+    /// Creates a new instance of the Program structure from source code written
+    /// in the given dialect.
+    ///
+    /// The line and column numbers of the instructions point to the tokens in the
+    /// original source. If the dialect has the [`Extension::Separator`], the text
+    /// after the first `!` becomes the input of the program. With the
+    /// [`Extension::Macros`] the macros are expanded like in
+    /// [`Program::with_macros`], which is the only way this can fail.
+    pub fn with_dialect(filename: String, content: &str, dialect: &Dialect) -> io::Result<Self> {
+        if dialect.has_extension(Extension::Macros) {
+            return Program::with_macros(filename, content, dialect);
+        }
+        Ok(Program::lexed(filename, content, dialect))
+    }
+
+    /// Lexes the source in the dialect, without expanding any macros.
+    fn lexed(filename: String, content: &str, dialect: &Dialect) -> Self {
+        let mut sources = SourceMap::new();
+        let file_id = sources.add(filename, content);
+        let (ins, input) = dialect.lex_with_input(file_id, content);
 
-        Program { filename, ins }
+        Program {
+            sources,
+            ins,
+            input,
+        }
     }
 
-    /// Returns the source code filename as String
-    pub fn source_file(self) -> String {
-        self.filename
+    /// Same as [`Program::with_dialect`], but the macros in the source are
+    /// expanded first, see [`Extension::Macros`].
+    ///
+    /// A line `#define name(a, b) body` defines a macro, `#const NAME 65` a
+    /// constant, and `X*N` repeats an instruction, a `{...}` group or a macro
+    /// use. The instructions from a macro point at the place it is used.
+    pub fn with_macros(filename: String, content: &str, dialect: &Dialect) -> io::Result<Self> {
+        let mut sources = SourceMap::new();
+        let file_id = sources.add(filename.clone(), content);
+        let (ins, input) = Macros::default().lex(dialect, file_id, &filename, content)?;
+
+        Ok(Program {
+            sources,
+            ins,
+            input,
+        })
+    }
+
+    /// Creates a program from instructions made by a tool, like a compiler,
+    /// with the sources their spans point into.
+    pub fn from_instructions(sources: SourceMap, ins: Vec<Instruction>) -> Self {
+        Program {
+            sources,
+            ins,
+            input: None,
+        }
+    }
+
+    /// Creates a program running other instructions, like a rewritten version
+    /// of this one, with the same input after the `!` separator.
+    pub fn with_instructions(&self, sources: SourceMap, ins: Vec<Instruction>) -> Self {
+        Program {
+            sources,
+            ins,
+            input: self.input.clone(),
+        }
+    }
+
+    /// Returns the filename of the main source file
+    pub fn source_file(&self) -> &str {
+        self.sources.name(0).unwrap_or_default()
+    }
+
+    /// Returns the source files of the program, to show the source of an
+    /// instruction's [`Span`].
+    pub fn source_map(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    /// Returns the input data written in the source after the `!` separator.
+    pub fn input(&self) -> Option<&str> {
+        self.input.as_deref()
     }
 
     /// Rerturns a slice to the internal instructions
@@ -126,58 +315,120 @@ impl Program {
     /// Validates the instructions for bracket matching.
     ///
     /// For every open braket, there must be a corresponding closing bracket.
+    /// The pbrain procedure parentheses must match in the same way, and a loop
+    /// can not cross the border of a procedure.
     /// This function makes sure to validate, the brackets, and returns error with the position
     /// of the bracket in the source code.
     pub fn validate(&self) -> Result<(), std::io::Error> {
-        let mut stack: Vec<(usize, usize)> = Vec::new();
+        // The open bracket or parenthesis with its position
+        let mut stack: Vec<(char, String)> = Vec::new();
 
         for instruction in self.ins.iter() {
-            match instruction {
-                Instruction::JumpForward(l, c) => {
-                    stack.push((*l, *c));
+            let at = self.describe(&instruction.span);
+            match instruction.op {
+                Opcode::JumpForward => {
+                    stack.push(('[', at));
                 }
-                Instruction::JumpBack(l, c) => {
+                Opcode::ProcedureStart => {
+                    stack.push(('(', at));
+                }
+                Opcode::JumpBack => match stack.pop() {
+                    Some(('[', _)) => (),
                     // Means extra closing bracket
-                    if stack.is_empty() {
+                    None => {
                         return Err(std::io::Error::new(
                             std::io::ErrorKind::InvalidInput,
-                            format!("Extra close bracket at line {} column {}.", *l, *c),
+                            format!("Extra close bracket at {}.", at),
                         ));
-                    } else {
-                        stack.pop();
                     }
-                }
+                    Some((_, open)) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "Close bracket at {} ends the procedure opened at {}.",
+                                at, open
+                            ),
+                        ));
+                    }
+                },
+                Opcode::ProcedureEnd => match stack.pop() {
+                    Some(('(', _)) => (),
+                    None => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Extra close parenthesis at {}.", at),
+                        ));
+                    }
+                    Some((_, open)) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "Close parenthesis at {} ends the loop opened at {}.",
+                                at, open
+                            ),
+                        ));
+                    }
+                },
                 _ => (),
             }
         }
 
-        if !stack.is_empty() {
+        if let Some((open, at)) = stack.pop() {
             // Means extra open brackets in our code
-            let (l, c) = stack.pop().unwrap();
+            let kind = if open == '[' {
+                "bracket"
+            } else {
+                "parenthesis"
+            };
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                format!("Extra open bracket at line {} column {}.", l, c),
+                format!("Extra open {} at {}.", kind, at),
             ));
         }
 
         Ok(())
     }
+
+    /// Analyses the program without running it, for byte cells: how far the
+    /// head can go, and which loops are balanced or never end.
+    ///
+    /// Fails like [`Program::validate`] when the brackets do not match.
+    pub fn analyze(&self) -> io::Result<Analysis> {
+        self.analyze_cells(u8::MAX as u64)
+    }
+
+    /// Analyses the program like [`Program::analyze`], for cells holding
+    /// values up to `cell_max`.
+    pub fn analyze_cells(&self, cell_max: u64) -> io::Result<Analysis> {
+        self.validate()?;
+        Ok(analysis::analyze(&self.ins, cell_max))
+    }
+
+    /// Explains the program by recognising the common idioms in it, like
+    /// clearing, moving or multiplying cells and printing known bytes.
+    ///
+    /// Fails like [`Program::validate`] when the brackets do not match.
+    pub fn explain(&self) -> io::Result<Vec<Annotation>> {
+        self.validate()?;
+        Ok(explain::explain(&self.ins))
+    }
+
+    /// Describes where the span is for error messages, as `line 1 column 2`,
+    /// with the file name if it is in an included file.
+    pub fn describe(&self, span: &Span) -> String {
+        match self.sources.name(span.file_id) {
+            Some(name) if span.file_id != 0 => format!("{} of {}", span, name),
+            _ => span.to_string(),
+        }
+    }
 }
 
 impl fmt::Display for Program {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for ins in self.instructions() {
-            let _ = match ins {
-                Instruction::IncrementDP(_, _) => write!(f, ">"),
-                Instruction::DecrementDP(_, _) => write!(f, "<"),
-                Instruction::IncrementByte(_, _) => write!(f, "+"),
-                Instruction::DecrementByte(_, _) => write!(f, "-"),
-                Instruction::Output(_, _) => write!(f, "."),
-                Instruction::Input(_, _) => write!(f, ","),
-                Instruction::JumpForward(_, _) => write!(f, "["),
-                Instruction::JumpBack(_, _) => write!(f, "]"),
-                _ => Ok(()),
-            };
+            if let Some(ch) = ins.as_char() {
+                write!(f, "{}", ch)?;
+            }
         }
         write!(f, "")
     }
@@ -185,7 +436,7 @@ impl fmt::Display for Program {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Instruction, Program};
+    use crate::{Dialect, Extension, Opcode, Program, Span};
     use std::io::{Error, ErrorKind};
 
     #[test]
@@ -198,12 +449,16 @@ mod tests {
         // Now create the program
         let p = Program::new("test.bf".to_string(), &input);
         let ins = p.instructions();
-        assert_eq!(ins[0], Instruction::IncrementDP(1, 1));
-        assert_eq!(ins[1], Instruction::DecrementDP(2, 1));
-        assert_eq!(ins[2], Instruction::IncrementByte(2, 2));
-        assert_eq!(ins[3], Instruction::DecrementByte(2, 3));
-        assert_eq!(ins[4], Instruction::Output(2, 4));
-        assert_eq!(ins[5], Instruction::Input(2, 5));
+        assert_eq!(ins[0].op, Opcode::IncrementDP);
+        assert_eq!(ins[0].span, Span::new(1, 1, 0));
+        assert_eq!(ins[1].op, Opcode::DecrementDP);
+        assert_eq!(ins[1].span, Span::new(2, 1, 2));
+        assert_eq!(ins[2].op, Opcode::IncrementByte);
+        assert_eq!(ins[3].op, Opcode::DecrementByte);
+        assert_eq!(ins[4].op, Opcode::Output);
+        assert_eq!(ins[5].op, Opcode::Input);
+        assert_eq!(ins[5].position(), (2, 5));
+        assert_eq!(p.source_map().line_text(&ins[5].span), Some("<+-.,"));
     }
 
     #[test]
@@ -234,4 +489,25 @@ mod tests {
         );
         assert_eq!(error.to_string(), expected.to_string());
     }
+
+    #[test]
+    fn parse_procedure_parentheses() {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Pbrain);
+        let p = Program::with_dialect("test.bf".to_string(), "+([-]):", &dialect).unwrap();
+        assert!(p.validate().is_ok());
+        assert_eq!(p.instructions()[1].op, Opcode::ProcedureStart);
+        assert_eq!(p.instructions()[6].op, Opcode::CallProcedure);
+        assert_eq!(p.instructions()[6].position(), (1, 7));
+
+        let p = Program::with_dialect("test.bf".to_string(), "([)]", &dialect).unwrap();
+        assert_eq!(
+            p.validate().err().unwrap().to_string(),
+            "Close parenthesis at line 1 column 3 ends the loop opened at line 1 column 2."
+        );
+        let p = Program::with_dialect("test.bf".to_string(), "+(", &dialect).unwrap();
+        assert_eq!(
+            p.validate().err().unwrap().to_string(),
+            "Extra open parenthesis at line 1 column 2."
+        );
+    }
 }
@@ -0,0 +1,511 @@
+//! An opt-in macro layer, expanded to plain Brainfuck before lexing.
+//!
+//! With the [`crate::Extension::Macros`] a source can use:
+//!
+//! - `#define name(a, b) body` and `#define name body` lines, the body is the
+//!   rest of the line, and the parameters are replaced by the arguments.
+//! - `#const NAME 65` lines, for numbers used as repetition counts.
+//! - `name(x, y)` or `name` to use a macro, a whole word like in C.
+//! - `X*N` to repeat `X` N times, where `X` is one instruction character, a
+//!   group `{...}` or a macro use, and `N` a number or a constant.
+//!
+//! Every character of the expansion remembers the [`Span`] it came from. A
+//! macro body maps to the place the macro is used, so the errors and the
+//! traces point at the use site.
+
+use crate::{Dialect, Instruction, Span};
+use std::collections::HashMap;
+use std::io;
+
+/// Deepest nesting of macro uses, to stop runaway expansions.
+const MAX_DEPTH: usize = 64;
+
+/// Largest repetition count.
+const MAX_COUNT: usize = 100_000;
+
+/// Most characters a group or macro use may expand to, so that nested
+/// repetitions can not take all the memory either.
+const MAX_EXPANSION: usize = 1_000_000;
+
+/// A character along with where it came from.
+type Spanned = (char, Span);
+
+/// The result of an expansion, the error has the span it is about.
+type Expanded<T> = Result<T, (String, Span)>;
+
+/// A macro defined with `#define`.
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: String,
+}
+
+/// The macros and constants defined so far.
+#[derive(Debug, Default)]
+pub(crate) struct Macros {
+    defs: HashMap<String, Macro>,
+    consts: HashMap<String, usize>,
+}
+
+impl Macros {
+    /// Reads the definitions in `content`, expands it and lexes the expansion.
+    ///
+    /// The spans of the instructions point into `content` as the file
+    /// `file_id`, named `filename` in the error messages.
+    pub(crate) fn lex(
+        &mut self,
+        dialect: &Dialect,
+        file_id: usize,
+        filename: &str,
+        content: &str,
+    ) -> io::Result<(Vec<Instruction>, Option<String>)> {
+        // The input after the separator is not expanded
+        let (program, input) = dialect.split_input(content, is_definition);
+        let mut source = Vec::with_capacity(program.len());
+        let (mut line, mut col, mut byte_offset) = (1, 1, 0);
+        for text in program.split_inclusive('\n') {
+            let definition = self
+                .define(text)
+                .map_err(|msg| error(msg, filename, Span::new(line, 1, byte_offset)))?;
+            for ch in text.chars() {
+                // The definitions leave only their line end
+                if !definition || ch == '\n' {
+                    source.push((ch, Span::new(line, col, byte_offset).in_file(file_id)));
+                }
+                byte_offset += ch.len_utf8();
+                col += 1;
+            }
+            line += 1;
+            col = 1;
+        }
+
+        let mut expanded = Vec::with_capacity(source.len());
+        let mut stack = Vec::new();
+        self.expand(&source, None, &mut stack, &mut expanded)
+            .map_err(|(msg, span)| error(msg, filename, span))?;
+
+        let mut text = String::with_capacity(expanded.len());
+        // The span of every byte of the expanded text
+        let mut spans = Vec::with_capacity(expanded.len());
+        for (ch, span) in expanded {
+            text.push(ch);
+            spans.extend(std::iter::repeat_n(span, ch.len_utf8()));
+        }
+        let mut ins = dialect.lex_program(file_id, &text);
+        for ins in ins.iter_mut() {
+            ins.span = spans[ins.span.byte_offset];
+        }
+        Ok((ins, input.map(|input| input.to_string())))
+    }
+
+    /// Reads a `#define` or `#const` line, and tells us if the line was one.
+    fn define(&mut self, line: &str) -> Result<bool, String> {
+        let line = line.trim();
+        if let Some(rest) = const_args(line) {
+            let mut words = rest.split_whitespace();
+            let (name, value) = match (words.next(), words.next(), words.next()) {
+                (Some(name), Some(value), None) if is_name(name) => (name, value),
+                _ => return Err("Malformed constant, use #const NAME 42".to_string()),
+            };
+            let value = self
+                .count(value)
+                .ok_or_else(|| format!("Constant {} must be a number, not {}", name, value))?;
+            self.consts.insert(name.to_string(), value);
+            return Ok(true);
+        }
+        let rest = match line.strip_prefix("#define") {
+            Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim_start(),
+            Some(_) => return Err("Malformed macro, use #define name(a, b) body".to_string()),
+            None => return Ok(false),
+        };
+        let name_end = rest
+            .find(|ch: char| !(ch.is_alphanumeric() || ch == '_'))
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        if !is_name(name) {
+            return Err("Malformed macro, use #define name(a, b) body".to_string());
+        }
+        let rest = &rest[name_end..];
+        let (params, body) = match rest.strip_prefix('(') {
+            Some(rest) => {
+                let close = rest
+                    .find(')')
+                    .ok_or_else(|| format!("Missing ) after the parameters of {}", name))?;
+                let params: Vec<String> = rest[..close]
+                    .split(',')
+                    .map(|param| param.trim().to_string())
+                    .filter(|param| !param.is_empty())
+                    .collect();
+                if let Some(param) = params.iter().find(|param| !is_name(param)) {
+                    return Err(format!("Bad parameter {} in macro {}", param, name));
+                }
+                (params, &rest[close + 1..])
+            }
+            None => (Vec::new(), rest),
+        };
+        self.defs.insert(
+            name.to_string(),
+            Macro {
+                params,
+                body: body.trim().to_string(),
+            },
+        );
+        Ok(true)
+    }
+
+    /// Expands `source` into `out`.
+    ///
+    /// Inside a macro body `site` is the span of the outermost use, and every
+    /// character of the body gets that span. `stack` holds the macros being
+    /// expanded.
+    fn expand(
+        &self,
+        source: &[Spanned],
+        site: Option<Span>,
+        stack: &mut Vec<String>,
+        out: &mut Vec<Spanned>,
+    ) -> Expanded<()> {
+        let mut pos = 0;
+        while pos < source.len() {
+            let (ch, span) = source[pos];
+            let mut atom = Vec::new();
+            let repeatable;
+            if ch.is_alphabetic() || ch == '_' {
+                let end = word_end(source, pos);
+                let name: String = source[pos..end].iter().map(|(ch, _)| ch).collect();
+                match self.defs.get(&name) {
+                    Some(mac) => {
+                        let (args, next) = self.arguments(&name, mac, source, end, span)?;
+                        if stack.contains(&name) {
+                            return Err((format!("Macro {} expands itself", name), span));
+                        }
+                        if stack.len() >= MAX_DEPTH {
+                            return Err((
+                                format!("Macro {} is nested more than {} deep", name, MAX_DEPTH),
+                                span,
+                            ));
+                        }
+                        let site = site.unwrap_or(span);
+                        let body = substitute(mac, &args, site);
+                        stack.push(name);
+                        self.expand(&body, Some(site), stack, &mut atom)?;
+                        stack.pop();
+                        pos = next;
+                        repeatable = true;
+                    }
+                    None => {
+                        // Only a comment
+                        out.extend_from_slice(&source[pos..end]);
+                        pos = end;
+                        continue;
+                    }
+                }
+            } else if ch == '{' {
+                let close = matching(source, pos, '{', '}')
+                    .ok_or_else(|| ("Group without a closing }".to_string(), span))?;
+                self.expand(&source[pos + 1..close], site, stack, &mut atom)?;
+                pos = close + 1;
+                repeatable = true;
+            } else if ch == '}' {
+                return Err(("Extra } without a group".to_string(), span));
+            } else {
+                atom.push(source[pos]);
+                pos += 1;
+                repeatable = !(ch.is_whitespace() || ch.is_alphanumeric());
+            }
+
+            let mut count = 1;
+            if repeatable && source.get(pos).map(|(ch, _)| *ch) == Some('*') {
+                let end = word_end(source, pos + 1);
+                let word: String = source[pos + 1..end].iter().map(|(ch, _)| ch).collect();
+                if let Some(value) = self.count(&word) {
+                    if value > MAX_COUNT {
+                        return Err((
+                            format!(
+                                "Repetition count {} is more than the limit of {}",
+                                word, MAX_COUNT
+                            ),
+                            source[pos].1,
+                        ));
+                    }
+                    count = value;
+                    pos = end;
+                }
+            }
+            if out.len() + atom.len() * count > MAX_EXPANSION {
+                return Err((
+                    format!(
+                        "Expansion is longer than the limit of {} characters",
+                        MAX_EXPANSION
+                    ),
+                    span,
+                ));
+            }
+            for _ in 0..count {
+                out.extend_from_slice(&atom);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the arguments of a macro use, starting after its name at `pos`,
+    /// and returns them with the position after the use.
+    fn arguments(
+        &self,
+        name: &str,
+        mac: &Macro,
+        source: &[Spanned],
+        pos: usize,
+        span: Span,
+    ) -> Expanded<(Vec<Vec<Spanned>>, usize)> {
+        if mac.params.is_empty() {
+            return Ok((Vec::new(), pos));
+        }
+        if source.get(pos).map(|(ch, _)| *ch) != Some('(') {
+            return Err((
+                format!("Macro {} needs {} arguments", name, mac.params.len()),
+                span,
+            ));
+        }
+        let close = matching(source, pos, '(', ')')
+            .ok_or_else(|| (format!("Missing ) after the arguments of {}", name), span))?;
+        let mut args = vec![Vec::new()];
+        let mut depth = 0;
+        for item in &source[pos + 1..close] {
+            match item.0 {
+                '(' | '{' => depth += 1,
+                ')' | '}' => depth -= 1,
+                ',' if depth == 0 => {
+                    args.push(Vec::new());
+                    continue;
+                }
+                _ => (),
+            }
+            args.last_mut().unwrap().push(*item);
+        }
+        if args.len() != mac.params.len() {
+            return Err((
+                format!(
+                    "Macro {} needs {} arguments, not {}",
+                    name,
+                    mac.params.len(),
+                    args.len()
+                ),
+                span,
+            ));
+        }
+        let args = args
+            .into_iter()
+            .map(|arg| {
+                let start = arg.iter().position(|(ch, _)| !ch.is_whitespace());
+                let end = arg.iter().rposition(|(ch, _)| !ch.is_whitespace());
+                match (start, end) {
+                    (Some(start), Some(end)) => arg[start..=end].to_vec(),
+                    _ => Vec::new(),
+                }
+            })
+            .collect();
+        Ok((args, close + 1))
+    }
+
+    /// Reads a repetition count, a number or a constant.
+    fn count(&self, word: &str) -> Option<usize> {
+        if !word.is_empty() && word.bytes().all(|byte| byte.is_ascii_digit()) {
+            // A number too big to parse is over any limit
+            return Some(word.parse().unwrap_or(usize::MAX));
+        }
+        self.consts.get(word).copied()
+    }
+}
+
+/// Returns the body of the macro with the parameters replaced by the
+/// arguments. The body gets the span of the use site, the arguments keep
+/// their own.
+fn substitute(mac: &Macro, args: &[Vec<Spanned>], site: Span) -> Vec<Spanned> {
+    let body: Vec<Spanned> = mac.body.chars().map(|ch| (ch, site)).collect();
+    let mut out = Vec::with_capacity(body.len());
+    let mut pos = 0;
+    while pos < body.len() {
+        let ch = body[pos].0;
+        if ch.is_alphabetic() || ch == '_' {
+            let end = word_end(&body, pos);
+            let word: String = body[pos..end].iter().map(|(ch, _)| ch).collect();
+            match mac.params.iter().position(|param| *param == word) {
+                Some(index) => out.extend_from_slice(&args[index]),
+                None => out.extend_from_slice(&body[pos..end]),
+            }
+            pos = end;
+        } else {
+            out.push(body[pos]);
+            pos += 1;
+        }
+    }
+    out
+}
+
+/// Returns the end of the word starting at `pos`.
+fn word_end(source: &[Spanned], pos: usize) -> usize {
+    source[pos..]
+        .iter()
+        .position(|(ch, _)| !(ch.is_alphanumeric() || *ch == '_'))
+        .map_or(source.len(), |len| pos + len)
+}
+
+/// Returns the index of the `close` matching the `open` at `pos`.
+fn matching(source: &[Spanned], pos: usize, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    for (index, (ch, _)) in source.iter().enumerate().skip(pos) {
+        if *ch == open {
+            depth += 1;
+        } else if *ch == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(index);
+            }
+        }
+    }
+    None
+}
+
+/// Tells us if the text is a valid macro or constant name.
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(ch) if ch.is_alphabetic() || ch == '_')
+        && chars.all(|ch| ch.is_alphanumeric() || ch == '_')
+}
+
+/// Returns the rest of the line after `#const`, if the line is a constant.
+fn const_args(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("#const")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+/// Tells if [`Macros::define`] reads the line, so a `!` there is not the
+/// input separator.
+pub(crate) fn is_definition(line: &str) -> bool {
+    line.trim().starts_with("#define") || const_args(line).is_some()
+}
+
+/// Creates the error for a problem in the macros.
+fn error(msg: String, filename: &str, span: Span) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} at {}:{}:{}.", msg, filename, span.line, span.col),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Dialect, Extension, Opcode, Program};
+
+    fn expand(source: &str) -> Result<Program, std::io::Error> {
+        let dialect = Dialect::brainfuck().with_extension(Extension::Macros);
+        Program::with_macros("test.bf".to_string(), source, &dialect)
+    }
+
+    #[test]
+    fn repetitions() {
+        let p = expand("+*5 {>-}*2 #const N 3\n#const N 3\n<*N x*2 {[-]}*0").unwrap();
+        // The #const line in the middle of the first line is only text
+        assert_eq!(p.to_string(), "+++++>->-<<<");
+        let last = p
+            .instructions()
+            .iter()
+            .rev()
+            .find(|ins| ins.op == Opcode::DecrementDP);
+        assert_eq!(last.unwrap().position(), (3, 1));
+
+        // The dialect is enough to expand them
+        let dialect = Dialect::brainfuck().with_extension(Extension::Macros);
+        let p = Program::with_dialect("test.bf".to_string(), "+*3", &dialect).unwrap();
+        assert_eq!(p.to_string(), "+++");
+    }
+
+    #[test]
+    fn macros_with_parameters() {
+        let source =
+            "#define move(to, back) [-to+back]\n#define right >\n\n+*3 move(right, <)\nright";
+        let p = expand(source).unwrap();
+        assert_eq!(p.to_string(), "+++[->+<]>");
+        // Expanded code points at the use site, arguments at themselves
+        let ins = p.instructions();
+        let open = ins
+            .iter()
+            .find(|ins| ins.op == Opcode::JumpForward)
+            .unwrap();
+        assert_eq!(open.position(), (4, 5));
+        assert_eq!(
+            p.source_map().line_text(&open.span),
+            Some("+*3 move(right, <)")
+        );
+        let back = ins
+            .iter()
+            .find(|ins| ins.op == Opcode::DecrementDP)
+            .unwrap();
+        assert_eq!(back.position(), (4, 17));
+        assert_eq!(ins.last().unwrap().position(), (5, 1));
+        // Commas inside a group are instructions
+        let p = expand("#define twice(x) x x\ntwice({,.})").unwrap();
+        assert_eq!(p.to_string(), ",.,.");
+    }
+
+    #[test]
+    fn macro_errors() {
+        let error = |source| expand(source).err().unwrap().to_string();
+        assert_eq!(
+            error("#define a b\n#define b a\n\n a"),
+            "Macro a expands itself at test.bf:4:2."
+        );
+        assert_eq!(
+            error("#define m(x) x\nm(+, -)"),
+            "Macro m needs 1 arguments, not 2 at test.bf:2:1."
+        );
+        assert_eq!(error("+{>"), "Group without a closing } at test.bf:1:2.");
+        assert_eq!(
+            error("\n+*99999999999"),
+            "Repetition count 99999999999 is more than the limit of 100000 at test.bf:2:2."
+        );
+        assert_eq!(
+            error("{{+*1000}*1000}*1000"),
+            "Expansion is longer than the limit of 1000000 characters at test.bf:1:1."
+        );
+        // A chain of 70 macros, each using the next one
+        let chain: String = (0..70)
+            .map(|index| format!("#define m{} m{}\n", index, index + 1))
+            .collect();
+        assert_eq!(
+            expand(&format!("{}m0", chain)).err().unwrap().to_string(),
+            "Macro m64 is nested more than 64 deep at test.bf:71:1."
+        );
+        assert_eq!(
+            error("#const X y"),
+            "Constant X must be a number, not y at test.bf:1:1."
+        );
+        assert_eq!(
+            error("#const\n"),
+            "Malformed constant, use #const NAME 42 at test.bf:1:1."
+        );
+        // Only comments, so N is no constant
+        assert_eq!(expand("#constN 65\n+*N.").unwrap().to_string(), "+.");
+        assert_eq!(expand("#constant X 1\n+").unwrap().to_string(), "+");
+        // Brackets are checked on the expansion, at the use site
+        let p = expand("#define open [\n+open").unwrap();
+        assert_eq!(
+            p.validate().err().unwrap().to_string(),
+            "Extra open bracket at line 2 column 2."
+        );
+    }
+
+    #[test]
+    fn input_is_not_expanded() {
+        let dialect = Dialect::brainfuck()
+            .with_extension(Extension::Macros)
+            .with_extension(Extension::Separator);
+        let source = "#define A +\n#define bang !\n,.A bang!A\n#define B -\n#const N 1";
+        let p = Program::with_dialect("test.bf".to_string(), source, &dialect).unwrap();
+        // The ! of a definition is not the separator, nor the one it expands to
+        assert_eq!(p.to_string(), ",.+");
+        assert_eq!(p.input(), Some("A\n#define B -\n#const N 1"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Source positions of the instructions, and the source files they point into.
+
+use std::fmt;
+
+/// Where an instruction was written in the source.
+///
+/// `file_id` is the index of the file in the [`SourceMap`] of the program,
+/// `byte_offset` is counted from the start of that file, and `line` and `col`
+/// start at 1. The column counts characters, not bytes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Hash)]
+pub struct Span {
+    /// The file in the [`SourceMap`].
+    pub file_id: usize,
+    /// Byte offset of the first character of the token.
+    pub byte_offset: usize,
+    /// Line number, starting at 1.
+    pub line: usize,
+    /// Column number on the line, starting at 1.
+    pub col: usize,
+}
+
+impl Span {
+    /// Creates a span in the first file of the program.
+    pub fn new(line: usize, col: usize, byte_offset: usize) -> Self {
+        Span {
+            file_id: 0,
+            byte_offset,
+            line,
+            col,
+        }
+    }
+
+    /// Returns the same position in another file.
+    pub fn in_file(self, file_id: usize) -> Self {
+        Span { file_id, ..self }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} column {}", self.line, self.col)
+    }
+}
+
+/// One source file of a program.
+#[derive(Debug, Clone)]
+struct SourceFile {
+    name: String,
+    content: String,
+    /// Byte offsets of the first character of every line.
+    line_starts: Vec<usize>,
+}
+
+/// All the source files of a program, so that a [`Span`] can be turned back
+/// into the file name and the text of the line for diagnostics.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    /// Creates an empty source map.
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    /// Adds a file, and returns its `file_id`.
+    pub fn add(&mut self, name: String, content: &str) -> usize {
+        let line_starts = std::iter::once(0)
+            .chain(content.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        self.files.push(SourceFile {
+            name,
+            content: content.to_string(),
+            line_starts,
+        });
+        self.files.len() - 1
+    }
+
+    /// Number of files in the map.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Tells us if there are no files in the map.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Returns the name of the file.
+    pub fn name(&self, file_id: usize) -> Option<&str> {
+        self.files.get(file_id).map(|file| file.name.as_str())
+    }
+
+    /// Returns the whole content of the file.
+    pub fn content(&self, file_id: usize) -> Option<&str> {
+        self.files.get(file_id).map(|file| file.content.as_str())
+    }
+
+    /// Returns the text of the line the span is on, without the line end.
+    pub fn line_text(&self, span: &Span) -> Option<&str> {
+        let file = self.files.get(span.file_id)?;
+        let start = *file.line_starts.get(span.line.checked_sub(1)?)?;
+        let end = file
+            .line_starts
+            .get(span.line)
+            .copied()
+            .unwrap_or(file.content.len());
+        Some(file.content[start..end].trim_end_matches(['\n', '\r']))
+    }
+
+    /// Returns the span as `file:line:col`.
+    pub fn location(&self, span: &Span) -> String {
+        format!(
+            "{}:{}:{}",
+            self.name(span.file_id).unwrap_or("<unknown>"),
+            span.line,
+            span.col
+        )
+    }
+
+    /// Returns the location, the line of source and a `^` under the column,
+    /// ready to be printed below an error message.
+    pub fn snippet(&self, span: &Span) -> Option<String> {
+        let text = self.line_text(span)?;
+        let indent: String = text
+            .chars()
+            .take(span.col.saturating_sub(1))
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        Some(format!(
+            "{}\n    {}\n    {}^",
+            self.location(span),
+            text,
+            indent
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::span::{SourceMap, Span};
+
+    #[test]
+    fn line_text() {
+        let mut map = SourceMap::new();
+        map.add("a.bf".to_string(), "+\n");
+        let id = map.add("b.bf".to_string(), "++\r\n\t[-]\nend");
+        assert_eq!(id, 1);
+        let span = Span::new(2, 3, 5).in_file(id);
+        assert_eq!(map.line_text(&span), Some("\t[-]"));
+        assert_eq!(map.line_text(&Span::new(3, 1, 0).in_file(id)), Some("end"));
+        assert_eq!(map.line_text(&Span::new(4, 1, 0).in_file(id)), None);
+        assert_eq!(map.snippet(&span).unwrap(), "b.bf:2:3\n    \t[-]\n    \t ^");
+    }
+}
@@ -1,5 +1,9 @@
 //! The code to handle all cli related parts
+use bft_interp::{DumpFormat, EofMode, FlushPolicy, Storage};
+use bft_types::Extension;
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -9,11 +13,258 @@ pub struct Opt {
     #[structopt(short, long)]
     pub extensible: bool,
 
+    /// Let the tape grow on demand in both directions, so the head can move left
+    /// of the first cell.
+    #[structopt(long)]
+    pub bidirectional: bool,
+
+    /// How the cells are stored: dense, or sparse to only keep the touched
+    /// cells in memory.
+    #[structopt(long, default_value = "dense")]
+    pub storage: Storage,
+
     /// Set the number of cells in the virtual machine, default 30000.
     #[structopt(short, long)]
     pub cells: Option<usize>,
 
-    /// Input source code
+    /// Size the tape to the cells the program can reach, when the analysis of
+    /// the program can bound them.
+    #[structopt(long, conflicts_with = "cells")]
+    pub fit_tape: bool,
+
+    /// Print the tape to stderr when the program ends or fails.
+    #[structopt(long)]
+    pub dump_tape: bool,
+
+    /// Format of the tape dump: hex, dec or xxd.
+    #[structopt(long, default_value = "xxd")]
+    pub dump_format: DumpFormat,
+
+    /// Load the initial tape contents from this file, one byte per cell.
+    #[structopt(long, parse(from_os_str), conflicts_with = "tape")]
+    pub tape_init: Option<PathBuf>,
+
+    /// Initial tape contents as comma separated cell values, like "1,2,3".
+    #[structopt(long)]
+    pub tape: Option<String>,
+
+    /// Initial position of the head on the tape, default 0.
+    #[structopt(long)]
+    pub head: Option<usize>,
+
+    /// What `,` does at the end of the input: unchanged, zero or max.
+    #[structopt(long, default_value = "unchanged")]
+    pub eof: EofMode,
+
+    /// When to flush the output: after every byte, every line, or only at exit.
+    #[structopt(long, default_value = "line")]
+    pub flush: FlushPolicy,
+
+    /// Stop the program with an error after executing this many instructions.
+    #[structopt(long)]
+    pub step_limit: Option<u64>,
+
+    /// Language of the source code: bf, ook, blub, or a TOML file with a custom
+    /// token mapping.
+    #[structopt(long, default_value = "bf")]
+    pub dialect: String,
+
+    /// Comma separated opt-in instruction sets, like ebf1 for Extended Type I.
+    #[structopt(long, use_delimiter = true, number_of_values = 1)]
+    pub extensions: Vec<Extension>,
+
+    /// Shape of the memory: tape, or 2d for a grid where ^ and v move the head
+    /// up and down.
+    #[structopt(long, default_value = "tape")]
+    pub mode: Mode,
+
+    /// Number of cells in every row of the 2d grid.
+    #[structopt(long, default_value = "100")]
+    pub grid_width: usize,
+
+    /// Write the cells as a .pgm or .ppm image to this file when the program
+    /// ends or fails.
+    #[structopt(long, parse(from_os_str))]
+    pub dump_image: Option<PathBuf>,
+
+    /// Input source code, required unless a subcommand is given
     #[structopt(name = "PROGRAM", parse(from_os_str))]
-    pub program: PathBuf,
+    pub program: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+// The subcommands of bft, without one we just run the given program.
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Runs every .bf program in DIR, feeding it the .in file and comparing its
+    /// output with the .out file. An optional .toml file can set cells,
+    /// extensible, eof and step_limit for the program.
+    Test {
+        /// Directory holding the programs and their fixtures
+        #[structopt(name = "DIR", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    /// Translates a program from one dialect to another, and prints it.
+    Convert {
+        /// Dialect of the source code: bf, ook, blub, or a TOML file
+        #[structopt(long, default_value = "bf")]
+        from: String,
+
+        /// Dialect to write the program in: bf, ook, blub, or a TOML file
+        #[structopt(long)]
+        to: String,
+
+        /// Copy the comments of the source code too
+        #[structopt(long)]
+        keep_comments: bool,
+
+        /// Comma separated opt-in instruction sets, for both dialects
+        #[structopt(long, use_delimiter = true, number_of_values = 1)]
+        extensions: Vec<Extension>,
+
+        /// Input source code
+        #[structopt(name = "PROGRAM", parse(from_os_str))]
+        program: PathBuf,
+    },
+    /// Compiles a program in the structured bft language to Brainfuck, and
+    /// prints it.
+    Compile {
+        /// Dialect to write the program in: bf, ook, blub, or a TOML file
+        #[structopt(long, default_value = "bf")]
+        to: String,
+
+        /// Source code in the bft language
+        #[structopt(name = "PROGRAM", parse(from_os_str))]
+        program: PathBuf,
+    },
+    /// Analyses the program without running it, and warns about loops which
+    /// never end and heads which can fall off the tape.
+    Analyze {
+        /// Dialect of the source code: bf, ook, blub, or a TOML file
+        #[structopt(long, default_value = "bf")]
+        from: String,
+
+        /// Comma separated opt-in instruction sets
+        #[structopt(long, use_delimiter = true, number_of_values = 1)]
+        extensions: Vec<Extension>,
+
+        /// Input source code
+        #[structopt(name = "PROGRAM", parse(from_os_str))]
+        program: PathBuf,
+    },
+    /// Explains the program, with the pseudo code of the idioms it uses next
+    /// to where they are.
+    Explain {
+        /// Dialect of the source code: bf, ook, blub, or a TOML file
+        #[structopt(long, default_value = "bf")]
+        from: String,
+
+        /// Comma separated opt-in instruction sets
+        #[structopt(long, use_delimiter = true, number_of_values = 1)]
+        extensions: Vec<Extension>,
+
+        /// Input source code
+        #[structopt(name = "PROGRAM", parse(from_os_str))]
+        program: PathBuf,
+    },
+    /// Runs the program up to where it reads input ahead of time, and prints
+    /// it with that part replaced by code setting the tape directly.
+    Fold {
+        /// Dialect of the source code: bf, ook, blub, or a TOML file
+        #[structopt(long, default_value = "bf")]
+        from: String,
+
+        /// Dialect to write the program in: bf, ook, blub, or a TOML file
+        #[structopt(long, default_value = "bf")]
+        to: String,
+
+        /// Comma separated opt-in instruction sets, for both dialects
+        #[structopt(long, use_delimiter = true, number_of_values = 1)]
+        extensions: Vec<Extension>,
+
+        /// Most instructions to run ahead of time
+        #[structopt(long, default_value = "1000000")]
+        step_limit: u64,
+
+        /// Input source code
+        #[structopt(name = "PROGRAM", parse(from_os_str))]
+        program: PathBuf,
+    },
+    /// Generates a short program printing the text, and prints it.
+    ///
+    /// The length of the program goes to stderr.
+    GenPrint {
+        /// Dialect to write the program in: bf, ook, blub, or a TOML file
+        #[structopt(long, default_value = "bf")]
+        to: String,
+
+        /// Print a line end after the text
+        #[structopt(short, long)]
+        newline: bool,
+
+        /// Text for the program to print
+        #[structopt(name = "TEXT")]
+        text: String,
+    },
+}
+
+/// The shape of the memory, chosen with `--mode`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Mode {
+    /// One dimensional tape.
+    Tape,
+    /// Two dimensional grid.
+    Grid,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tape" | "1d" => Ok(Mode::Tape),
+            "2d" | "grid" => Ok(Mode::Grid),
+            _ => Err(format!("Unknown mode {}, use tape or 2d.", s)),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Tape => write!(f, "tape"),
+            Mode::Grid => write!(f, "2d"),
+        }
+    }
+}
+
+/// Parses comma separated cell values like `1,2,3` into bytes.
+pub fn parse_tape(values: &str) -> Result<Vec<u8>, String> {
+    values
+        .split(',')
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<u8>()
+                .map_err(|_| format!("Invalid tape cell value {}.", value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::parse_tape;
+
+    #[test]
+    fn parse_tape_values() {
+        assert_eq!(parse_tape("1, 2,3"), Ok(vec![1, 2, 3]));
+        assert_eq!(parse_tape(""), Ok(vec![]));
+        assert_eq!(
+            parse_tape("1,256"),
+            Err("Invalid tape cell value 256.".to_string())
+        );
+    }
 }
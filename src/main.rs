@@ -1,11 +1,15 @@
-use bft_interp::VirtualMachine;
-use bft_types::Program;
+use bft_interp::{ImageFormat, MemoryModel, VirtualMachine};
+use bft_types::{Dialect, Extension, Program};
 use std::env;
 use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
 use std::process;
 use structopt::StructOpt;
 
 mod cli;
+mod test_runner;
 /// Generic Error for the code readability.
 type GError = Box<dyn Error>;
 
@@ -14,11 +18,47 @@ type GError = Box<dyn Error>;
 /// Requires a source code as the argument.
 fn main() {
     let options = cli::Opt::from_args();
-    let res = run_bft(options);
+    let res = match options.cmd {
+        Some(cli::Command::Test { ref dir }) => test_runner::run_tests(dir),
+        Some(cli::Command::Convert {
+            ref from,
+            ref to,
+            keep_comments,
+            ref extensions,
+            ref program,
+        }) => convert(from, to, keep_comments, extensions, program),
+        Some(cli::Command::Compile {
+            ref to,
+            ref program,
+        }) => compile(to, program),
+        Some(cli::Command::Analyze {
+            ref from,
+            ref extensions,
+            ref program,
+        }) => analyze(from, extensions, program),
+        Some(cli::Command::Explain {
+            ref from,
+            ref extensions,
+            ref program,
+        }) => explain(from, extensions, program),
+        Some(cli::Command::Fold {
+            ref from,
+            ref to,
+            ref extensions,
+            step_limit,
+            ref program,
+        }) => fold(from, to, extensions, step_limit, program),
+        Some(cli::Command::GenPrint {
+            ref to,
+            newline,
+            ref text,
+        }) => gen_print(to, newline, text),
+        None => run_bft(options),
+    };
     if res.is_err() {
         if let Ok(path) = env::current_exe() {
             let exe = path.file_name().unwrap();
-            let msg = format!("{:?}: {}", exe, res.err().unwrap().to_string());
+            let msg = format!("{:?}: {}", exe, res.err().unwrap());
             eprintln!("{}", msg);
         }
         process::exit(1);
@@ -30,16 +70,220 @@ fn main() {
 
 /// Entry point to the bft code base
 fn run_bft(options: cli::Opt) -> Result<(), GError> {
-    let filename = options.program;
-    let program = Program::from_file(filename)?;
+    let filename = match options.program {
+        Some(filename) => filename,
+        None => return Err("No program given to run.".into()),
+    };
+    let mut extensions = options.extensions.clone();
+    let memory = match options.mode {
+        cli::Mode::Tape => MemoryModel::Tape,
+        cli::Mode::Grid => {
+            extensions.push(Extension::Grid);
+            MemoryModel::Grid(options.grid_width)
+        }
+    };
+    let image_format = match options.dump_image {
+        Some(ref path) => Some(image_format(path)?),
+        None => None,
+    };
+    let dialect = load_dialect(&options.dialect, &extensions)?;
+    let program = Program::from_file_with_dialect(filename, &dialect)?;
 
     program.validate()?;
 
-    let size = options.cells.unwrap_or(0);
+    let tape = match (&options.tape_init, &options.tape) {
+        (Some(path), _) => fs::read(path)?,
+        (None, Some(values)) => cli::parse_tape(values)?,
+        (None, None) => Vec::new(),
+    };
+
+    let mut builder = VirtualMachine::builder()
+        .tape_size(options.cells.unwrap_or(0))
+        .growable(options.extensible)
+        .fit_tape(options.fit_tape)
+        .tape(&tape)
+        .head(options.head.unwrap_or(0))
+        .eof(options.eof)
+        .memory(memory)
+        .bidirectional(options.bidirectional)
+        .storage(options.storage)
+        .flush_policy(options.flush)
+        .output(std::io::stdout());
+    // The input after the `!` separator replaces stdin
+    if program.input().is_none() {
+        builder = builder.input(std::io::stdin());
+    }
+    if let Some(limit) = options.step_limit {
+        builder = builder.step_limit(limit);
+    }
+    let mut vm = builder.build(program)?;
+    let res = match vm.run() {
+        // The reader of our output is gone, like in `bft prog.bf | head`
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        res => res,
+    };
+    if options.dump_tape {
+        vm.dump_tape(&mut std::io::stderr(), options.dump_format)?;
+    }
+    if let (Some(path), Some(format)) = (&options.dump_image, image_format) {
+        let mut file = fs::File::create(path)?;
+        vm.dump_image(&mut file, format)?;
+    }
+    res?;
+    Ok(())
+}
+
+/// Picks the image format from the extension of the file name.
+fn image_format(path: &Path) -> Result<ImageFormat, GError> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    extension
+        .parse::<ImageFormat>()
+        .map_err(|_| format!("Image dump {} must be a .pgm or .ppm file.", path.display()).into())
+}
 
-    let mut vm = VirtualMachine::new(size, options.extensible, program);
-    let mut stdin = std::io::stdin();
+/// Reads the program in one dialect and prints it in another.
+fn convert(
+    from: &str,
+    to: &str,
+    keep_comments: bool,
+    extensions: &[Extension],
+    filename: &Path,
+) -> Result<(), GError> {
+    let from = load_dialect(from, extensions)?;
+    let to = load_dialect(to, extensions)?;
+    let program = Program::from_file_with_dialect(filename, &from)?;
+    writeln!(std::io::stdout(), "{}", to.emit(&program, keep_comments)?)?;
+    Ok(())
+}
+
+/// Compiles a program in the bft language and prints the Brainfuck.
+fn compile(to: &str, filename: &Path) -> Result<(), GError> {
+    let to = load_dialect(to, &[])?;
+    let source = fs::read_to_string(filename)?;
+    let program = bft_lang::compile(filename.display().to_string(), &source)?;
+    writeln!(std::io::stdout(), "{}", to.emit(&program, false)?)?;
+    Ok(())
+}
+
+/// Prints what the analysis of the program found, with a warning per line for
+/// the loops which never end, the `<` which can fall off the tape, and the
+/// loops which are not balanced or never reached.
+fn analyze(from: &str, extensions: &[Extension], filename: &Path) -> Result<(), GError> {
+    let from = load_dialect(from, extensions)?;
+    let program = Program::from_file_with_dialect(filename, &from)?;
+    let analysis = program.analyze()?;
+    let sources = program.source_map();
     let mut out = std::io::stdout();
-    vm.interpret(&mut stdin, &mut out)?;
+    match analysis.tape_size() {
+        Some(size) => writeln!(out, "Tape: at most {} cells", size)?,
+        None => writeln!(out, "Tape: not bounded")?,
+    }
+    let balanced = analysis.loops.iter().filter(|l| l.is_balanced()).count();
+    writeln!(
+        out,
+        "Loops: {} balanced, {} not balanced",
+        balanced,
+        analysis.loops.len() - balanced
+    )?;
+    for span in analysis.underflows.iter() {
+        writeln!(
+            out,
+            "{}: < can move the head left of the first cell",
+            sources.location(span)
+        )?;
+    }
+    for report in analysis.loops.iter() {
+        let at = sources.location(&report.start);
+        if report.never_ends {
+            writeln!(out, "{}: loop never ends", at)?;
+        } else if !report.reached {
+            writeln!(out, "{}: loop is never reached", at)?;
+        }
+        match report.shift {
+            Some(0) => (),
+            Some(shift) => writeln!(out, "{}: loop moves the head by {} every round", at, shift)?,
+            None => writeln!(out, "{}: loop moves the head by a varying amount", at)?,
+        }
+    }
+    Ok(())
+}
+
+/// Prints the idioms of the program, one per line, with the range of
+/// positions they cover.
+fn explain(from: &str, extensions: &[Extension], filename: &Path) -> Result<(), GError> {
+    let from = load_dialect(from, extensions)?;
+    let program = Program::from_file_with_dialect(filename, &from)?;
+    let mut out = std::io::stdout();
+    for annotation in program.explain()? {
+        let (start, end) = (annotation.start, annotation.end);
+        let mut range = format!("{}:{}-{}:{}", start.line, start.col, end.line, end.col);
+        if start.file_id != 0 {
+            let name = program.source_map().name(start.file_id).unwrap_or("");
+            range = format!("{}:{}", name, range);
+        }
+        writeln!(
+            out,
+            "{:<14} {:<9} {}{}",
+            range,
+            annotation.idiom,
+            "    ".repeat(annotation.depth),
+            annotation.summary
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints the program with its input-free prefix folded, and what was folded
+/// to stderr.
+fn fold(
+    from: &str,
+    to: &str,
+    extensions: &[Extension],
+    step_limit: u64,
+    filename: &Path,
+) -> Result<(), GError> {
+    let from = load_dialect(from, extensions)?;
+    let to = load_dialect(to, extensions)?;
+    let program = Program::from_file_with_dialect(filename, &from)?;
+    let prefix = bft_interp::run_prefix(&program, step_limit)?;
+    let folded = prefix.apply(&program);
+    writeln!(std::io::stdout(), "{}", to.emit(&folded, false)?)?;
+    eprintln!(
+        "{} steps folded, {} instructions instead of {}",
+        prefix.steps,
+        folded.instructions().len(),
+        program.instructions().len()
+    );
     Ok(())
 }
+
+/// Prints a program printing the text, and its length to stderr.
+fn gen_print(to: &str, newline: bool, text: &str) -> Result<(), GError> {
+    let to = load_dialect(to, &[])?;
+    let mut text = text.as_bytes().to_vec();
+    if newline {
+        text.push(b'\n');
+    }
+    let program = bft_lang::gen_print(&text);
+    writeln!(std::io::stdout(), "{}", to.emit(&program, false)?)?;
+    eprintln!(
+        "{} instructions printing {} bytes",
+        program.instructions().len(),
+        text.len()
+    );
+    Ok(())
+}
+
+/// Finds the builtin dialect with this name, or reads a custom dialect from
+/// the file at this path, and adds the extensions to it.
+fn load_dialect(name: &str, extensions: &[Extension]) -> Result<Dialect, GError> {
+    let dialect = match Dialect::from_name(name) {
+        Some(dialect) => dialect,
+        None => {
+            Dialect::from_file(name).map_err(|e| format!("Can not load dialect {}: {}", name, e))?
+        }
+    };
+    Ok(extensions.iter().fold(dialect, |dialect, extension| {
+        dialect.with_extension(*extension)
+    }))
+}
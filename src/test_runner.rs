@@ -0,0 +1,217 @@
+//! Runs brainfuck programs against their expected input and output fixtures.
+//!
+//! For every `foo.bf` in the directory, `foo.in` is given as the input (an
+//! empty input if it is missing), and the output must match `foo.out`. The
+//! optional `foo.toml` can change the virtual machine settings.
+
+use crate::GError;
+use bft_interp::{EofMode, VirtualMachine};
+use bft_types::Program;
+use serde::Deserialize;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+/// Steps a program may take when its fixture sets no `step_limit`, so that a
+/// program which never ends fails instead of hanging.
+const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+
+/// Settings for one program, read from the `.toml` file next to it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Fixture {
+    /// Number of cells in the virtual machine, default 30000.
+    cells: Option<usize>,
+    /// If the virtual machine can grow in size.
+    extensible: bool,
+    /// What `,` does at the end of the input: unchanged, zero or max.
+    eof: Option<String>,
+    /// Maximum number of instructions the program may execute, default
+    /// 10000000.
+    step_limit: Option<u64>,
+}
+
+/// Runs all the programs in `dir` and its subdirectories, and prints a report.
+///
+/// Returns an error if any of the programs failed.
+pub fn run_tests(dir: &Path) -> Result<(), GError> {
+    let mut programs = Vec::new();
+    find_programs(dir, &mut programs)?;
+    programs.sort();
+
+    let mut failed = 0;
+    for path in programs.iter() {
+        match run_test(path) {
+            Ok(None) => println!("PASS {}", path.display()),
+            Ok(Some(diff)) => {
+                failed += 1;
+                println!("FAIL {}", path.display());
+                print!("{}", diff);
+            }
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}", path.display());
+                println!("  {}", e);
+            }
+        }
+    }
+
+    println!("\n{} passed, {} failed", programs.len() - failed, failed);
+    if failed > 0 {
+        return Err(format!("{} of {} tests failed.", failed, programs.len()).into());
+    }
+    Ok(())
+}
+
+/// Collects the paths of all `.bf` files under `dir`.
+fn find_programs(dir: &Path, programs: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_programs(&path, programs)?;
+        } else if path.extension().is_some_and(|ext| ext == "bf") {
+            programs.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reads a fixture file, which may not exist.
+fn read_optional(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs one program with its fixtures.
+///
+/// Returns `Ok(None)` when the output matches, or the differences otherwise.
+fn run_test(path: &Path) -> Result<Option<String>, GError> {
+    let fixture = match read_optional(&path.with_extension("toml"))? {
+        Some(content) => toml::from_slice(&content)?,
+        None => Fixture::default(),
+    };
+    let input = read_optional(&path.with_extension("in"))?.unwrap_or_default();
+    let expected_path = path.with_extension("out");
+    let expected = match read_optional(&expected_path)? {
+        Some(content) => content,
+        None => {
+            return Err(format!("Missing expected output {}.", expected_path.display()).into());
+        }
+    };
+
+    let program = Program::from_file(path)?;
+    program.validate()?;
+
+    let mut vm = VirtualMachine::new(fixture.cells.unwrap_or(0), fixture.extensible, program);
+    if let Some(eof) = fixture.eof {
+        vm.set_eof_mode(eof.parse::<EofMode>()?);
+    }
+    vm.set_step_limit(Some(fixture.step_limit.unwrap_or(DEFAULT_STEP_LIMIT)));
+
+    let mut output = Vec::new();
+    vm.interpret(&mut Cursor::new(input), &mut output)?;
+
+    if output == expected {
+        Ok(None)
+    } else {
+        Ok(Some(diff(&expected, &output)))
+    }
+}
+
+/// Shows the lines which are different between the expected and the actual output.
+fn diff(expected: &[u8], actual: &[u8]) -> String {
+    let expected = String::from_utf8_lossy(expected);
+    let actual = String::from_utf8_lossy(actual);
+    let expected: Vec<&str> = expected.split('\n').collect();
+    let actual: Vec<&str> = actual.split('\n').collect();
+
+    let mut result = String::new();
+    for index in 0..expected.len().max(actual.len()) {
+        let left = expected.get(index);
+        let right = actual.get(index);
+        if left == right {
+            continue;
+        }
+        result.push_str(&format!("  line {}:\n", index + 1));
+        if let Some(line) = left {
+            result.push_str(&format!("  - {:?}\n", line));
+        }
+        if let Some(line) = right {
+            result.push_str(&format!("  + {:?}\n", line));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_runner::{diff, run_test};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Creates an empty directory for one test.
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bft-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn passing_program() {
+        let dir = fixture_dir("pass");
+        // Echo the input back, one byte at a time
+        fs::write(dir.join("echo.bf"), ",[.,]").unwrap();
+        fs::write(dir.join("echo.in"), "hello\n").unwrap();
+        fs::write(dir.join("echo.out"), "hello\n").unwrap();
+        fs::write(dir.join("echo.toml"), "eof = \"zero\"\ncells = 10\n").unwrap();
+
+        assert_eq!(run_test(&dir.join("echo.bf")).unwrap(), None);
+    }
+
+    #[test]
+    fn failing_program() {
+        let dir = fixture_dir("fail");
+        fs::write(dir.join("a.bf"), "++++++++[>++++++++<-]>+.").unwrap();
+        fs::write(dir.join("a.out"), "B").unwrap();
+
+        assert_eq!(
+            run_test(&dir.join("a.bf")).unwrap(),
+            Some("  line 1:\n  - \"B\"\n  + \"A\"\n".to_string())
+        );
+    }
+
+    #[test]
+    fn step_limit_from_fixture() {
+        let dir = fixture_dir("limit");
+        fs::write(dir.join("loop.bf"), "+[]").unwrap();
+        fs::write(dir.join("loop.out"), "").unwrap();
+        fs::write(dir.join("loop.toml"), "step_limit = 50").unwrap();
+
+        let error = run_test(&dir.join("loop.bf")).err().unwrap();
+        assert_eq!(error.to_string(), "Step limit of 50 reached.");
+    }
+
+    #[test]
+    fn default_step_limit() {
+        let dir = fixture_dir("endless");
+        // Without eof = "zero" the end of the input never stops the loop
+        fs::write(dir.join("echo.bf"), ",[.,]").unwrap();
+        fs::write(dir.join("echo.in"), "a").unwrap();
+        fs::write(dir.join("echo.out"), "a").unwrap();
+
+        let error = run_test(&dir.join("echo.bf")).err().unwrap();
+        assert_eq!(error.to_string(), "Step limit of 10000000 reached.");
+    }
+
+    #[test]
+    fn diff_extra_lines() {
+        assert_eq!(
+            diff(b"a\nb", b"a\nc\nd"),
+            "  line 2:\n  - \"b\"\n  + \"c\"\n  line 3:\n  + \"d\"\n"
+        );
+    }
+}